@@ -0,0 +1,86 @@
+use lyon::math::{point, Box2D};
+use pinxerit::texture::SamplerOptions;
+use pinxerit::{canvas, run, App, Config, Context, TextureHandle};
+
+#[derive(Default)]
+struct Demo {
+    floor: Option<TextureHandle>,
+}
+
+impl App for Demo {
+    fn init(&mut self, context: &mut Context) {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/src/floor.png");
+        self.floor = context
+            .textures
+            .load_path(
+                context.device,
+                context.queue,
+                path,
+                &SamplerOptions::smooth(),
+            )
+            .ok();
+    }
+
+    fn draw(&mut self, canvas: &mut canvas::Canvas) {
+        if let Some(floor) = &self.floor {
+            canvas.draw_texture(floor, Box2D::new(point(-1.0, -1.0), point(-0.6, -0.6)));
+        }
+        draw_demo(canvas);
+    }
+}
+
+fn main() {
+    let config = Config {
+        // Edit the shaders or floor.png while the demo runs.
+        hot_reload: cfg!(debug_assertions),
+        ..Config::default()
+    };
+    pollster::block_on(run(Demo::default(), config));
+}
+
+fn draw_demo(canvas: &mut canvas::Canvas) {
+    let mut clip = canvas::Line::start(-0.5, -0.5, [0.; 4]);
+    clip.to(0.5, -0.5);
+    clip.to(0.5, 0.5);
+    clip.to(-0.5, 0.5);
+    clip.clip(canvas);
+
+    let mut line = canvas::Line::start(-1., -1., [0.8, 0.2, 0.5, 1.0]);
+    line.to(1., 1.);
+    line.to(1., -1.);
+    line.end(canvas);
+
+    canvas.pop_clip();
+
+    canvas.push_scissor(canvas::ScissorRect::new(0, 0, 200, 200));
+    let mut line = canvas::Line::start(-1., 1., [0.2, 0.8, 0.5, 1.0]);
+    line.to(1., -1.);
+    line.end(canvas);
+    canvas.pop_scissor();
+
+    canvas.begin_mask();
+    let mut mask = canvas::Line::start(-0.8, 0.2, [1.0; 4]);
+    mask.to(-0.2, 0.2);
+    mask.to(-0.5, 0.8);
+    mask.clip(canvas);
+    let mut fill = canvas::Line::start(-1., 0., [1.0; 4]);
+    fill.to(0., 1.);
+    fill.end(canvas);
+    canvas.pop_clip();
+    let mask = canvas.end_mask(canvas::MaskMode::Alpha);
+
+    canvas.push_layer(0.5, Some(mask));
+    for i in 0..10 {
+        let x = -1. + i as f32 * 0.1;
+        let mut line = canvas::Line::start(x, 0., [0.9, 0.9, 0.2, 1.0]);
+        line.to(x + 0.2, 1.);
+        line.end(canvas);
+    }
+    canvas.pop_layer();
+
+    let mut line = canvas::Line::start(0.2, 0.2, [0.9, 0.9, 0.9, 1.0]);
+    line.to(0.8, 0.2);
+    line.to(0.8, 0.8);
+    line.shadow(canvas::Shadow::new([6., 8.], 4., [0., 0., 0., 0.6]));
+    line.end(canvas);
+}
@@ -0,0 +1,44 @@
+use std::path::Path;
+use std::time::Duration;
+
+use winit::event::Event;
+use winit::window::Window;
+
+use crate::canvas::Canvas;
+use crate::input::{Input, InputEvent};
+use crate::material::MaterialPipeline;
+use crate::reload::HotReload;
+use crate::store::TextureStore;
+
+/// What an `App` gets to set itself up once the window and GPU are ready.
+pub struct Context<'a> {
+    pub device: &'a wgpu::Device,
+    pub queue: &'a wgpu::Queue,
+    pub window: &'a Window,
+    /// Where textures for `Canvas::draw_texture` are loaded.
+    pub textures: &'a mut TextureStore,
+    /// Where materials for `Canvas::set_material` are registered.
+    pub materials: &'a mut MaterialPipeline,
+    /// Set with `Config::hot_reload`, to watch more files, see `App::file_changed`.
+    pub hot_reload: Option<&'a mut HotReload>,
+}
+
+/// User code driven by `run`. Every frame `update` is called with the input state and the time
+/// since the previous frame, then `draw` fills a freshly cleared canvas that is rendered right
+/// after. With `Config::retained` the canvas keeps the shapes of earlier frames.
+pub trait App {
+    fn init(&mut self, _context: &mut Context) {}
+
+    fn update(&mut self, _input: &Input, _dt: Duration) {}
+
+    fn draw(&mut self, canvas: &mut Canvas);
+
+    /// Called for every input event as it arrives, before the next `update`.
+    fn input(&mut self, _event: &InputEvent) {}
+
+    /// Called before a frame for every file added with `HotReload::watch` that changed.
+    fn file_changed(&mut self, _context: &mut Context, _path: &Path) {}
+
+    /// Called for every winit event before pinxerit handles it.
+    fn event(&mut self, _event: &Event<()>) {}
+}
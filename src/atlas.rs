@@ -0,0 +1,290 @@
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use image::{DynamicImage, RgbaImage};
+use lyon::math::{point, Box2D};
+use wgpu::{Device, Queue};
+
+use crate::canvas::{Canvas, ShapeId};
+use crate::store::{TextureHandle, TextureStore};
+use crate::texture::{SamplerOptions, Texture};
+
+/// Empty pixels around every image, filled with copies of its edge so linear filtering
+/// never picks up its neighbours.
+const PADDING: u32 = 1;
+
+/// Packs many small images into a few large textures. Shapes drawn from images of the same
+/// page share a texture, so the renderer draws consecutive ones with a single call.
+pub struct TextureAtlas {
+    /// The width and height of new pages. Images that do not fit get a page of their own.
+    pub page_size: u32,
+    /// How new pages are sampled. Pages have no mipmaps, since images are added to them
+    /// after they are uploaded.
+    pub sampler: SamplerOptions,
+    pages: Vec<AtlasPage>,
+}
+
+struct AtlasPage {
+    texture: TextureHandle,
+    packer: RectPacker,
+}
+
+/// An image of a `TextureAtlas`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AtlasImage {
+    /// The page the image is on.
+    pub texture: TextureHandle,
+    /// Where the image is on the page, in texture coordinates from 0 to 1 with y down.
+    pub uv: Box2D,
+    /// The size of the image in pixels.
+    pub size: (u32, u32),
+}
+
+impl AtlasImage {
+    /// Draws the image stretched over `rect`, see `Canvas::draw_texture_region`.
+    pub fn draw(&self, canvas: &mut Canvas, rect: Box2D) -> ShapeId {
+        canvas.draw_texture_region(&self.texture, self.uv, rect)
+    }
+}
+
+impl TextureAtlas {
+    pub fn new(page_size: u32) -> TextureAtlas {
+        TextureAtlas {
+            page_size,
+            sampler: SamplerOptions::default(),
+            pages: vec![],
+        }
+    }
+
+    /// The number of textures the images are spread over.
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn add_path(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        textures: &mut TextureStore,
+        path: impl AsRef<Path>,
+    ) -> Result<AtlasImage> {
+        let image = image::open(path)?;
+        self.add_image(device, queue, textures, &image)
+    }
+
+    pub fn add_bytes(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        textures: &mut TextureStore,
+        bytes: &[u8],
+    ) -> Result<AtlasImage> {
+        let image = image::load_from_memory(bytes)?;
+        self.add_image(device, queue, textures, &image)
+    }
+
+    /// Copies `image` into the first page with room for it, adding a page when none has.
+    /// Fails for images without pixels.
+    pub fn add_image(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        textures: &mut TextureStore,
+        image: &DynamicImage,
+    ) -> Result<AtlasImage> {
+        if image.width() == 0 || image.height() == 0 {
+            bail!(
+                "cannot add an empty {}x{} image to the atlas",
+                image.width(),
+                image.height()
+            );
+        }
+        let padded = pad(&image.to_rgba8());
+        let (width, height) = padded.dimensions();
+        let found = self.pages.iter_mut().enumerate().find_map(|(index, page)| {
+            page.packer
+                .allocate(width, height)
+                .map(|origin| (index, origin))
+        });
+        let (page, origin) = match found {
+            Some(found) => found,
+            None => {
+                let mut packer =
+                    RectPacker::new(self.page_size.max(width), self.page_size.max(height));
+                let origin = packer.allocate(width, height).unwrap();
+                let (page_width, page_height) = packer.size();
+                let blank = DynamicImage::ImageRgba8(RgbaImage::new(page_width, page_height));
+                let sampler = SamplerOptions {
+                    mipmaps: None,
+                    ..self.sampler
+                };
+                let texture =
+                    Texture::from_image(device, queue, &blank, Some("atlas_page"), &sampler)?;
+                self.pages.push(AtlasPage {
+                    texture: textures.insert(device, texture),
+                    packer,
+                });
+                (self.pages.len() - 1, origin)
+            }
+        };
+
+        let page = &self.pages[page];
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &textures.get(&page.texture).unwrap().texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d {
+                    x: origin[0],
+                    y: origin[1],
+                    z: 0,
+                },
+                aspect: wgpu::TextureAspect::All,
+            },
+            &padded,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * width),
+                rows_per_image: Some(height),
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+
+        let (page_width, page_height) = page.packer.size();
+        let (x, y) = (origin[0] + PADDING, origin[1] + PADDING);
+        let size = (image.width(), image.height());
+        Ok(AtlasImage {
+            texture: page.texture.clone(),
+            uv: Box2D::new(
+                point(x as f32 / page_width as f32, y as f32 / page_height as f32),
+                point(
+                    (x + size.0) as f32 / page_width as f32,
+                    (y + size.1) as f32 / page_height as f32,
+                ),
+            ),
+            size,
+        })
+    }
+}
+
+/// Surrounds `image`, which must not be empty, with `PADDING` pixels repeating its edges.
+fn pad(image: &RgbaImage) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    RgbaImage::from_fn(width + 2 * PADDING, height + 2 * PADDING, |x, y| {
+        let x = x.saturating_sub(PADDING).min(width - 1);
+        let y = y.saturating_sub(PADDING).min(height - 1);
+        *image.get_pixel(x, y)
+    })
+}
+
+/// Places rectangles in rows, called shelves, from the top of an area down. A rectangle
+/// goes to the flattest shelf that still fits it, a new shelf is opened below the others
+/// otherwise.
+#[derive(Debug, Clone)]
+pub struct RectPacker {
+    width: u32,
+    height: u32,
+    shelves: Vec<Shelf>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Shelf {
+    y: u32,
+    height: u32,
+    /// Where the next rectangle on the shelf goes.
+    x: u32,
+}
+
+impl RectPacker {
+    pub fn new(width: u32, height: u32) -> RectPacker {
+        RectPacker {
+            width,
+            height,
+            shelves: vec![],
+        }
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Returns the top left corner of a free `width` by `height` area, or `None` when
+    /// there is no room left.
+    pub fn allocate(&mut self, width: u32, height: u32) -> Option<[u32; 2]> {
+        if width > self.width {
+            return None;
+        }
+        let shelf = self
+            .shelves
+            .iter_mut()
+            .filter(|shelf| shelf.height >= height && self.width - shelf.x >= width)
+            .min_by_key(|shelf| shelf.height);
+        if let Some(shelf) = shelf {
+            let origin = [shelf.x, shelf.y];
+            shelf.x += width;
+            return Some(origin);
+        }
+        let y = self
+            .shelves
+            .last()
+            .map_or(0, |shelf| shelf.y + shelf.height);
+        if self.height - y < height {
+            return None;
+        }
+        self.shelves.push(Shelf {
+            y,
+            height,
+            x: width,
+        });
+        Some([0, y])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packer_fills_shelves_left_to_right() {
+        let mut packer = RectPacker::new(10, 10);
+        assert_eq!(packer.allocate(4, 3), Some([0, 0]));
+        assert_eq!(packer.allocate(4, 2), Some([4, 0]));
+        // Too wide for the rest of the first shelf.
+        assert_eq!(packer.allocate(3, 3), Some([0, 3]));
+        assert_eq!(packer.allocate(2, 3), Some([8, 0]));
+    }
+
+    #[test]
+    fn packer_prefers_the_flattest_shelf() {
+        let mut packer = RectPacker::new(10, 10);
+        assert_eq!(packer.allocate(6, 5), Some([0, 0]));
+        assert_eq!(packer.allocate(6, 2), Some([0, 5]));
+        // Both shelves have room.
+        assert_eq!(packer.allocate(2, 2), Some([6, 5]));
+        assert_eq!(packer.allocate(2, 4), Some([6, 0]));
+    }
+
+    #[test]
+    fn packer_runs_out_of_room() {
+        let mut packer = RectPacker::new(10, 10);
+        assert_eq!(packer.allocate(11, 1), None);
+        assert_eq!(packer.allocate(10, 11), None);
+        assert_eq!(packer.allocate(10, 6), Some([0, 0]));
+        assert_eq!(packer.allocate(5, 5), None);
+        assert_eq!(packer.allocate(5, 4), Some([0, 6]));
+        assert_eq!(packer.allocate(5, 4), Some([5, 6]));
+        assert_eq!(packer.allocate(1, 1), None);
+    }
+
+    #[test]
+    fn pad_repeats_edges() {
+        let image = RgbaImage::from_fn(2, 1, |x, _| image::Rgba([x as u8, 0, 0, 255]));
+        let padded = pad(&image);
+        assert_eq!(padded.dimensions(), (4, 3));
+        let row: Vec<u8> = (0..4).map(|x| padded.get_pixel(x, 0)[0]).collect();
+        assert_eq!(row, vec![0, 0, 1, 1]);
+    }
+}
@@ -0,0 +1,255 @@
+use anyhow::Result;
+use wgpu::{Device, Queue, SurfaceConfiguration};
+
+use crate::reload;
+use crate::texture::Texture;
+
+/// Texels sampled on each side of the center are capped so huge blurs stay affordable.
+const MAX_RADIUS: u32 = 64;
+
+/// The largest standard deviation blurs are drawn with, in pixels. The gaussian is cut off
+/// at three of them, which with `MAX_RADIUS` leaves about 21 pixels; larger ones are
+/// clamped, see `Canvas::set_blur` and `Shadow::blur`.
+pub const MAX_BLUR_SIGMA: f32 = MAX_RADIUS as f32 / 3.0;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct BlurUniform {
+    direction: [f32; 2],
+    sigma: f32,
+    radius: i32,
+    /// The texture coordinates of the last texel center of the blurred area.
+    max_coords: [f32; 2],
+}
+
+pub struct BlurPipeline {
+    pub render_pipeline: wgpu::RenderPipeline,
+    pub bind_group_layout: wgpu::BindGroupLayout,
+}
+
+/// The uniforms and bind groups of the two passes blurring one texture, created once per
+/// source and scratch texture with `BlurPipeline::create_passes`.
+pub struct BlurPasses {
+    /// The horizontal pass from the source into the scratch texture, then the vertical one
+    /// from the scratch texture into the target.
+    passes: [(wgpu::Buffer, wgpu::BindGroup); 2],
+}
+
+/// The texels sampled on each side of the center for a blur with standard deviation `sigma`.
+pub fn radius(sigma: f32) -> u32 {
+    (sigma.clamp(0.0, MAX_BLUR_SIGMA) * 3.0).ceil() as u32
+}
+
+pub fn create_blur_pipeline(device: &Device, config: &SurfaceConfiguration) -> BlurPipeline {
+    let blur_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("Blur Shader"),
+        source: wgpu::ShaderSource::Wgsl(include_str!("shaders/blur_shader.wgsl").into()),
+    });
+
+    let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+        label: Some("blur_bind_group_layout"),
+    });
+
+    let render_pipeline = create_render_pipeline(device, config, &blur_shader, &bind_group_layout);
+
+    BlurPipeline {
+        render_pipeline,
+        bind_group_layout,
+    }
+}
+
+fn create_render_pipeline(
+    device: &Device,
+    config: &SurfaceConfiguration,
+    shader: &wgpu::ShaderModule,
+    bind_group_layout: &wgpu::BindGroupLayout,
+) -> wgpu::RenderPipeline {
+    let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Blur Render Pipeline Layout"),
+        bind_group_layouts: &[bind_group_layout],
+        push_constant_ranges: &[],
+    });
+
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Blur Render Pipeline"),
+        layout: Some(&render_pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_main",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format: config.format,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: None,
+            polygon_mode: wgpu::PolygonMode::Fill,
+            unclipped_depth: false,
+            conservative: false,
+        },
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState {
+            count: 1,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+    })
+}
+
+impl BlurPipeline {
+    /// Recreates the pipeline with `shader`, a new build of `blur_shader.wgsl`. The current
+    /// pipeline is kept when that fails.
+    pub fn set_shader(
+        &mut self,
+        device: &Device,
+        config: &SurfaceConfiguration,
+        shader: &wgpu::ShaderModule,
+    ) -> Result<()> {
+        self.render_pipeline = reload::capture_errors(device, || {
+            create_render_pipeline(device, config, shader, &self.bind_group_layout)
+        })?;
+        Ok(())
+    }
+
+    /// Creates the passes blurring `source` through `scratch`, which has the same size.
+    pub fn create_passes(
+        &self,
+        device: &Device,
+        source: &Texture,
+        scratch: &Texture,
+    ) -> BlurPasses {
+        BlurPasses {
+            passes: [source, scratch].map(|source| {
+                let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Blur Uniform Buffer"),
+                    size: std::mem::size_of::<BlurUniform>() as u64,
+                    usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                });
+                let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    layout: &self.bind_group_layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: wgpu::BindingResource::TextureView(&source.view),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: wgpu::BindingResource::Sampler(&source.sampler),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: uniform_buffer.as_entire_binding(),
+                        },
+                    ],
+                    label: Some("blur_bind_group"),
+                });
+                (uniform_buffer, bind_group)
+            }),
+        }
+    }
+
+    /// Blurs the top left `size` pixels of the source of `passes` into `target` with a
+    /// gaussian of standard deviation `sigma` (in pixels, at most `MAX_BLUR_SIGMA`), first
+    /// horizontally into `scratch`, then vertically. Texels outside of `size` are never
+    /// read, the edge of it is repeated instead. `target` may be the source. Each
+    /// `passes` can only be used once per frame, since its uniforms are written here.
+    #[allow(clippy::too_many_arguments)]
+    pub fn blur(
+        &self,
+        queue: &Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        passes: &BlurPasses,
+        scratch: &Texture,
+        target: &Texture,
+        size: (u32, u32),
+        sigma: f32,
+    ) {
+        let texture_size = scratch.texture.size();
+        let texel = [
+            1.0 / texture_size.width as f32,
+            1.0 / texture_size.height as f32,
+        ];
+        let directions = [[texel[0], 0.0], [0.0, texel[1]]];
+        for (((uniform_buffer, bind_group), direction), target) in
+            passes.passes.iter().zip(directions).zip([scratch, target])
+        {
+            let uniform = BlurUniform {
+                direction,
+                sigma: sigma.clamp(0.01, MAX_BLUR_SIGMA),
+                radius: radius(sigma) as i32,
+                max_coords: [
+                    (size.0 as f32 - 0.5) * texel[0],
+                    (size.1 as f32 - 0.5) * texel[1],
+                ],
+            };
+            queue.write_buffer(uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+            self.blur_pass(encoder, bind_group, target, size);
+        }
+    }
+
+    fn blur_pass(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        bind_group: &wgpu::BindGroup,
+        target: &Texture,
+        size: (u32, u32),
+    ) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Blur Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &target.view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+        render_pass.set_pipeline(&self.render_pipeline);
+        render_pass.set_bind_group(0, bind_group, &[]);
+        render_pass.set_scissor_rect(0, 0, size.0, size.1);
+        render_pass.draw(0..3, 0..1);
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::Arc;
+
+use lyon::lyon_tessellation::VertexBuffers;
+use lyon::math::Point;
+use lyon::path::{Path, PathEvent};
+
+use crate::canvas::PathStyle;
+use crate::tessellate::TessellateVertex;
+
+/// Tessellated geometry in the space of its path, without color, shared by every shape
+/// drawn with the same path and style.
+pub type Geometry = VertexBuffers<TessellateVertex, u32>;
+
+/// Keeps the tessellations of recently drawn paths so shapes drawn again, in any color and
+/// with any transform, skip the tessellator. The least recently used geometry is evicted
+/// once the cache holds more than `max_bytes` of vertex and index data.
+pub struct TessellationCache {
+    pub max_bytes: usize,
+    /// The entries by hash. Paths whose hashes collide share a bucket.
+    entries: HashMap<u64, Vec<CacheEntry>>,
+    /// The hash of every entry by when it was last used, oldest first.
+    lru: BTreeMap<u64, u64>,
+    bytes: usize,
+    tick: u64,
+}
+
+struct CacheEntry {
+    path: Path,
+    options: TessellateOptions,
+    geometry: Arc<Geometry>,
+    last_used: u64,
+}
+
+/// Everything besides the path that changes the tessellated geometry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TessellateOptions {
+    pub style: PathStyle,
+    pub feather: f32,
+    pub tolerance: f32,
+}
+
+impl TessellationCache {
+    pub fn new(max_bytes: usize) -> TessellationCache {
+        TessellationCache {
+            max_bytes,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            bytes: 0,
+            tick: 0,
+        }
+    }
+
+    /// Returns the geometry of `path` with `options`, calling `tessellate` only when it is
+    /// not cached yet.
+    pub fn get_or_insert_with(
+        &mut self,
+        path: &Path,
+        options: TessellateOptions,
+        tessellate: impl FnOnce() -> Geometry,
+    ) -> Arc<Geometry> {
+        match self.get(path, options) {
+            Some(geometry) => geometry,
+            None => self.insert(path.clone(), options, tessellate()),
+        }
+    }
+
+    pub fn get(&mut self, path: &Path, options: TessellateOptions) -> Option<Arc<Geometry>> {
+        self.tick += 1;
+        let key = hash_key(path, &options);
+        let entry = self
+            .entries
+            .get_mut(&key)?
+            .iter_mut()
+            .find(|entry| entry.matches(path, options))?;
+        self.lru.remove(&entry.last_used);
+        self.lru.insert(self.tick, key);
+        entry.last_used = self.tick;
+        Some(entry.geometry.clone())
+    }
+
+    pub fn insert(
+        &mut self,
+        path: Path,
+        options: TessellateOptions,
+        geometry: Geometry,
+    ) -> Arc<Geometry> {
+        let key = hash_key(&path, &options);
+        self.insert_with_key(key, path, options, geometry)
+    }
+
+    fn insert_with_key(
+        &mut self,
+        key: u64,
+        path: Path,
+        options: TessellateOptions,
+        geometry: Geometry,
+    ) -> Arc<Geometry> {
+        let geometry = Arc::new(geometry);
+        // Empty geometry takes no bytes, so the budget would never evict it, and paths or
+        // options with NaN never match themselves. Neither is worth keeping.
+        if geometry.indices.is_empty() || !is_finite(&path, &options) {
+            return geometry;
+        }
+        self.tick += 1;
+        let entry = CacheEntry {
+            path,
+            options,
+            geometry: geometry.clone(),
+            last_used: self.tick,
+        };
+        self.bytes += entry_bytes(&entry);
+        self.lru.insert(self.tick, key);
+        let bucket = self.entries.entry(key).or_default();
+        // Equal hashes of different paths are possible, only an equal path is replaced.
+        match bucket
+            .iter_mut()
+            .find(|cached| cached.matches(&entry.path, options))
+        {
+            Some(cached) => {
+                let replaced = mem::replace(cached, entry);
+                self.lru.remove(&replaced.last_used);
+                self.bytes -= entry_bytes(&replaced);
+            }
+            None => bucket.push(entry),
+        }
+        self.evict();
+        geometry
+    }
+
+    /// The number of cached geometries.
+    pub fn len(&self) -> usize {
+        self.lru.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The vertex and index data held by the cache, in bytes.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.lru.clear();
+        self.bytes = 0;
+    }
+
+    /// Removes the least recently used entries until the cache fits into `max_bytes`.
+    fn evict(&mut self) {
+        while self.bytes > self.max_bytes {
+            let Some((last_used, key)) = self.lru.pop_first() else {
+                break;
+            };
+            let bucket = self.entries.get_mut(&key).unwrap();
+            let index = bucket
+                .iter()
+                .position(|entry| entry.last_used == last_used)
+                .unwrap();
+            let entry = bucket.swap_remove(index);
+            if bucket.is_empty() {
+                self.entries.remove(&key);
+            }
+            self.bytes -= entry_bytes(&entry);
+        }
+    }
+}
+
+impl CacheEntry {
+    fn matches(&self, path: &Path, options: TessellateOptions) -> bool {
+        self.options == options && self.path.iter().eq(path.iter())
+    }
+}
+
+impl Default for TessellationCache {
+    /// A cache of 64 MiB.
+    fn default() -> TessellationCache {
+        TessellationCache::new(64 << 20)
+    }
+}
+
+fn entry_bytes(entry: &CacheEntry) -> usize {
+    entry.geometry.vertices.len() * mem::size_of::<TessellateVertex>()
+        + entry.geometry.indices.len() * mem::size_of::<u32>()
+}
+
+fn is_finite(path: &Path, options: &TessellateOptions) -> bool {
+    let finite = |point: Point| point.x.is_finite() && point.y.is_finite();
+    let width = match options.style {
+        PathStyle::Fill => 0.0,
+        PathStyle::Stroke { width } => width,
+    };
+    width.is_finite()
+        && options.feather.is_finite()
+        && options.tolerance.is_finite()
+        && path.iter().all(|event| match event {
+            PathEvent::Begin { at } => finite(at),
+            PathEvent::Line { to, .. } => finite(to),
+            PathEvent::Quadratic { ctrl, to, .. } => finite(ctrl) && finite(to),
+            PathEvent::Cubic {
+                ctrl1, ctrl2, to, ..
+            } => finite(ctrl1) && finite(ctrl2) && finite(to),
+            PathEvent::End { .. } => true,
+        })
+}
+
+fn hash_key(path: &Path, options: &TessellateOptions) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    for event in path.iter() {
+        match event {
+            PathEvent::Begin { at } => (0u8, at.x.to_bits(), at.y.to_bits()).hash(&mut hasher),
+            PathEvent::Line { to, .. } => (1u8, to.x.to_bits(), to.y.to_bits()).hash(&mut hasher),
+            PathEvent::Quadratic { ctrl, to, .. } => {
+                (2u8, ctrl.x.to_bits(), ctrl.y.to_bits()).hash(&mut hasher);
+                (to.x.to_bits(), to.y.to_bits()).hash(&mut hasher);
+            }
+            PathEvent::Cubic {
+                ctrl1, ctrl2, to, ..
+            } => {
+                (3u8, ctrl1.x.to_bits(), ctrl1.y.to_bits()).hash(&mut hasher);
+                (ctrl2.x.to_bits(), ctrl2.y.to_bits()).hash(&mut hasher);
+                (to.x.to_bits(), to.y.to_bits()).hash(&mut hasher);
+            }
+            PathEvent::End { close, .. } => (4u8, close).hash(&mut hasher),
+        }
+    }
+    match options.style {
+        PathStyle::Fill => 0u32.hash(&mut hasher),
+        PathStyle::Stroke { width } => (1u32, width.to_bits()).hash(&mut hasher),
+    }
+    (options.feather.to_bits(), options.tolerance.to_bits()).hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lyon::math::{point, Transform};
+
+    const OPTIONS: TessellateOptions = TessellateOptions {
+        style: PathStyle::Fill,
+        feather: 0.0,
+        tolerance: 0.01,
+    };
+
+    fn line(x: f32) -> Path {
+        let mut builder = Path::builder();
+        builder.begin(point(x, 0.0));
+        builder.line_to(point(x, 1.0));
+        builder.end(false);
+        builder.build()
+    }
+
+    /// Geometry of `bytes` bytes of indices.
+    fn geometry(bytes: usize) -> Geometry {
+        VertexBuffers {
+            vertices: vec![],
+            indices: vec![0; bytes / mem::size_of::<u32>()],
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = TessellationCache::new(12);
+        cache.insert(line(0.0), OPTIONS, geometry(4));
+        cache.insert(line(1.0), OPTIONS, geometry(4));
+        cache.insert(line(2.0), OPTIONS, geometry(4));
+        assert!(cache.get(&line(0.0), OPTIONS).is_some());
+        cache.insert(line(3.0), OPTIONS, geometry(4));
+
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.bytes(), 12);
+        assert!(cache.get(&line(1.0), OPTIONS).is_none());
+        assert!(cache.get(&line(0.0), OPTIONS).is_some());
+        assert!(cache.get(&line(2.0), OPTIONS).is_some());
+        assert!(cache.get(&line(3.0), OPTIONS).is_some());
+
+        // Evicts as many entries as it takes to fit.
+        cache.insert(line(4.0), OPTIONS, geometry(8));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&line(3.0), OPTIONS).is_some());
+        assert!(cache.get(&line(4.0), OPTIONS).is_some());
+    }
+
+    #[test]
+    fn insert_replaces_equal_path() {
+        let mut cache = TessellationCache::new(100);
+        cache.insert(line(0.0), OPTIONS, geometry(4));
+        cache.insert(line(0.0), OPTIONS, geometry(8));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.bytes(), 8);
+
+        let stroke = TessellateOptions {
+            style: PathStyle::Stroke { width: 1.0 },
+            ..OPTIONS
+        };
+        cache.insert(line(0.0), stroke, geometry(4));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&line(0.0), OPTIONS).unwrap().indices.len(), 2);
+    }
+
+    #[test]
+    fn skips_empty_geometry_and_nan_paths() {
+        let mut cache = TessellationCache::new(100);
+        cache.insert(line(0.0), OPTIONS, geometry(0));
+        // The path builder only rejects NaN in debug builds, transforms never do.
+        let nan = line(0.0).transformed(&Transform::translation(f32::NAN, 0.0));
+        cache.insert(nan, OPTIONS, geometry(4));
+        let nan_feather = TessellateOptions {
+            feather: f32::NAN,
+            ..OPTIONS
+        };
+        cache.insert(line(1.0), nan_feather, geometry(4));
+        assert!(cache.is_empty());
+        assert_eq!(cache.bytes(), 0);
+    }
+
+    #[test]
+    fn colliding_hashes_keep_both_paths() {
+        let mut cache = TessellationCache::new(8);
+        cache.insert_with_key(7, line(0.0), OPTIONS, geometry(4));
+        cache.insert_with_key(7, line(1.0), OPTIONS, geometry(4));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.entries[&7].len(), 2);
+
+        // Only the older of the two is evicted.
+        cache.insert(line(2.0), OPTIONS, geometry(4));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.entries[&7].len(), 1);
+        assert!(cache.entries[&7][0].matches(&line(1.0), OPTIONS));
+    }
+}
@@ -1,34 +1,1117 @@
+use std::mem;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
 use lyon::{
-    geom::Point,
+    algorithms::{aabb::bounding_box, hit_test::hit_test_path},
+    geom::{LineSegment, Point},
     lyon_tessellation::{
-        geometry_builder::simple_builder, StrokeOptions, StrokeTessellator, VertexBuffers,
+        BuffersBuilder, FillOptions, FillTessellator, FillVertex, Side, StrokeOptions,
+        StrokeTessellator, StrokeVertex, VertexBuffers,
     },
-    path::{path::Builder, Path},
+    math::{Box2D, Transform},
+    path::{iterator::PathIterator, path::Builder, FillRule, Path, PathEvent},
+};
+use rayon::prelude::*;
+use rstar::{
+    primitives::{GeomWithData, Rectangle},
+    Envelope, RTree, AABB,
 };
 
+use crate::cache::{Geometry, TessellateOptions, TessellationCache};
+use crate::colormap::{Colormap, Heatmap};
+use crate::material::MaterialId;
+use crate::sprite::{Sprite, SpriteBatch};
+use crate::store::TextureHandle;
 use crate::tessellate::TessellateVertex;
+use crate::texture::ValueMapping;
+
+/// Width of lines in canvas units, before the transform is applied.
+const LINE_WIDTH: f32 = 0.01;
+
+/// Curves are approximated by segments this close to them when hit testing.
+const HIT_TEST_FLATTENING: f32 = 0.0005;
+
+/// The bounding box of a shape in the spatial index, with its index in `Canvas::tessellates`.
+type IndexEntry = GeomWithData<Rectangle<[f32; 2]>, usize>;
 
 pub struct Canvas {
+    /// Shapes have to be added with `push_tessellate` to be found by queries and culling.
     pub tessellates: Vec<Tessellate>,
+    pub clips: Vec<Clip>,
+    pub layers: Vec<Layer>,
+    /// Width of the anti-aliased fringe added on both sides of strokes, in canvas units,
+    /// across which they fade out. Meant for a sample count of 1 where MSAA is too
+    /// expensive; 0 disables it.
+    pub feather: f32,
+    /// The maximum distance between curves and the segments approximating them.
+    pub tolerance: f32,
+    /// Kept by `clear`, so paths drawn every frame are only tessellated once.
+    pub cache: TessellationCache,
+    /// Defers the tessellation of added and changed shapes to `flush`, which tessellates
+    /// them all at once on the rayon thread pool. Until then they have no vertices and are
+    /// not found by queries like `hit_test`.
+    pub deferred: bool,
+    /// The shapes waiting for `flush`.
+    pending: Vec<usize>,
+    /// The sequence number of the first shape added to each layer or its child layers,
+    /// which is where the layer is placed among the items of its parent.
+    layer_first_shapes: Vec<Option<u64>>,
+    clip_stack: Vec<usize>,
+    scissor_stack: Vec<ScissorRect>,
+    layer_stack: Vec<usize>,
+    transform_stack: Vec<Transform>,
+    index: RTree<IndexEntry>,
+    /// Shapes added or changed since the last `flush`, which are not in `index` yet.
+    /// Queries check them one by one, and `flush` indexes them all at once.
+    unindexed: Vec<usize>,
+    /// When each shape was added, indexed like `tessellates`. Shapes are painted in this
+    /// order rather than by index, since the ids of removed shapes are given to new ones.
+    /// Removed shapes have `REMOVED`.
+    sequence: Vec<u64>,
+    next_sequence: u64,
+    /// The ids of removed shapes, which `push_tessellate` reuses.
+    free_shapes: Vec<usize>,
 }
 
 impl Canvas {
     pub fn new() -> Canvas {
         Canvas {
             tessellates: vec![],
+            clips: vec![],
+            layers: vec![],
+            feather: 0.0,
+            tolerance: StrokeOptions::DEFAULT_TOLERANCE,
+            cache: TessellationCache::default(),
+            deferred: false,
+            pending: vec![],
+            layer_first_shapes: vec![],
+            clip_stack: vec![],
+            scissor_stack: vec![],
+            layer_stack: vec![],
+            transform_stack: vec![],
+            index: RTree::new(),
+            unindexed: vec![],
+            sequence: vec![],
+            next_sequence: 0,
+            free_shapes: vec![],
+        }
+    }
+
+    /// Removes everything drawn so far while keeping settings like `feather`, the
+    /// tessellation cache and the allocations for the next frame.
+    pub fn clear(&mut self) {
+        self.tessellates.clear();
+        self.clips.clear();
+        self.layers.clear();
+        self.clip_stack.clear();
+        self.scissor_stack.clear();
+        self.layer_stack.clear();
+        self.transform_stack.clear();
+        self.index = RTree::new();
+        self.unindexed.clear();
+        self.pending.clear();
+        self.sequence.clear();
+        self.free_shapes.clear();
+        self.layer_first_shapes.clear();
+    }
+
+    /// Adds a shape on top of everything drawn so far, with the id of a removed shape if
+    /// there is one. Its bounding box is added to the spatial index by the next `flush`.
+    pub fn push_tessellate(&mut self, tessellate: Tessellate) -> ShapeId {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        let mut next = tessellate.layer;
+        while let Some(layer) = next {
+            self.layer_first_shapes[layer].get_or_insert(sequence);
+            next = self.layers[layer].parent;
+        }
+        let id = match self.free_shapes.pop() {
+            Some(index) => {
+                self.tessellates[index] = tessellate;
+                self.sequence[index] = sequence;
+                ShapeId(index)
+            }
+            None => {
+                self.tessellates.push(tessellate);
+                self.sequence.push(sequence);
+                ShapeId(self.tessellates.len() - 1)
+            }
+        };
+        self.index_shape(id);
+        id
+    }
+
+    /// Whether shapes are waiting to be tessellated by `flush`.
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Tessellates the shapes deferred since the last call in parallel, with the same result
+    /// as tessellating them one after the other, and adds the shapes added or changed since
+    /// then to the spatial index. `Renderer::render` calls it.
+    pub fn flush(&mut self) {
+        self.tessellate_pending();
+        self.index_pending();
+    }
+
+    fn tessellate_pending(&mut self) {
+        let mut pending = mem::take(&mut self.pending);
+        pending.sort_unstable();
+        pending.dedup();
+        // Ids of removed shapes may have gone to texture fills since, which are placed
+        // right away.
+        pending.retain(|&shape| self.tessellates[shape].texture.is_none());
+
+        let options: Vec<TessellateOptions> = pending
+            .iter()
+            .map(|&shape| TessellateOptions {
+                style: self.tessellates[shape].style,
+                feather: self.feather,
+                tolerance: self.tolerance,
+            })
+            .collect();
+        let cached: Vec<Option<Arc<Geometry>>> = pending
+            .iter()
+            .zip(&options)
+            .map(|(&shape, &options)| self.cache.get(&self.tessellates[shape].path, options))
+            .collect();
+
+        let tessellates = &self.tessellates;
+        let results: Vec<(Option<Geometry>, VertexBuffers<TessellateVertex, u32>)> = pending
+            .par_iter()
+            .zip(&options)
+            .zip(cached)
+            .map(|((&shape, options), cached)| {
+                let tessellate = &tessellates[shape];
+                match cached {
+                    Some(geometry) => {
+                        let buffers = instance(&geometry, &tessellate.transform, tessellate.color);
+                        (None, buffers)
+                    }
+                    None => {
+                        let geometry = tessellate_path(&tessellate.path, options);
+                        let buffers = instance(&geometry, &tessellate.transform, tessellate.color);
+                        (Some(geometry), buffers)
+                    }
+                }
+            })
+            .collect();
+
+        for ((shape, options), (geometry, buffers)) in pending.into_iter().zip(options).zip(results)
+        {
+            if let Some(geometry) = geometry {
+                let path = self.tessellates[shape].path.clone();
+                self.cache.insert(path, options, geometry);
+            }
+            self.set_buffers(ShapeId(shape), buffers);
+        }
+    }
+
+    /// Adds the unindexed shapes to the spatial index. When they are most of the shapes,
+    /// like after `clear`, the index is rebuilt with a bulk load, which is faster than
+    /// inserting them one at a time and gives a better tree.
+    fn index_pending(&mut self) {
+        let mut unindexed = mem::take(&mut self.unindexed);
+        unindexed.sort_unstable();
+        unindexed.dedup();
+        let entry = |index: usize| {
+            let tessellate = &self.tessellates[index];
+            (!tessellate.vertices.is_empty())
+                .then(|| GeomWithData::new(to_rectangle(&tessellate.bounds), index))
+        };
+        if unindexed.len() >= self.index.size() {
+            let entries = (0..self.tessellates.len()).filter_map(entry).collect();
+            self.index = RTree::bulk_load(entries);
+        } else {
+            let entries: Vec<IndexEntry> = unindexed.into_iter().filter_map(entry).collect();
+            for entry in entries {
+                self.index.insert(entry);
+            }
+        }
+    }
+
+    /// Applies `transform` to all following shapes, after the transform that is currently
+    /// active.
+    pub fn push_transform(&mut self, transform: Transform) {
+        let transform = transform.then(&self.current_transform());
+        self.transform_stack.push(transform);
+    }
+
+    pub fn pop_transform(&mut self) {
+        self.transform_stack.pop();
+    }
+
+    pub fn current_transform(&self) -> Transform {
+        self.transform_stack
+            .last()
+            .copied()
+            .unwrap_or(Transform::identity())
+    }
+
+    /// Restricts all following shapes to `rect` (in physical pixels), intersected with the
+    /// scissor that is currently active.
+    pub fn push_scissor(&mut self, rect: ScissorRect) {
+        let rect = match self.current_scissor() {
+            Some(current) => current.intersect(&rect),
+            None => rect,
+        };
+        self.scissor_stack.push(rect);
+    }
+
+    pub fn pop_scissor(&mut self) {
+        self.scissor_stack.pop();
+    }
+
+    pub fn current_scissor(&self) -> Option<ScissorRect> {
+        self.scissor_stack.last().copied()
+    }
+
+    /// Clip paths are pushed with `Line::clip`.
+    pub fn pop_clip(&mut self) {
+        self.clip_stack.pop();
+    }
+
+    pub fn current_clip(&self) -> Option<usize> {
+        self.clip_stack.last().copied()
+    }
+
+    /// Returns the nested clips that apply to `clip`, outermost first.
+    pub fn clip_chain(&self, clip: Option<usize>) -> Vec<usize> {
+        let mut chain = vec![];
+        let mut next = clip;
+        while let Some(index) = next {
+            chain.push(index);
+            next = self.clips[index].parent;
+        }
+        chain.reverse();
+        chain
+    }
+
+    /// Groups all following shapes until the matching `pop_layer` into a layer that is
+    /// rendered on its own and then composited with `opacity` and an optional `mask`.
+    pub fn push_layer(&mut self, opacity: f32, mask: Option<Mask>) {
+        self.layers.push(Layer {
+            opacity,
+            mask,
+            parent: self.current_layer(),
+            is_mask: false,
+            shadow: None,
+            blur: 0.0,
+            z_index: 0,
+        });
+        self.layer_first_shapes.push(None);
+        self.layer_stack.push(self.layers.len() - 1);
+    }
+
+    pub fn pop_layer(&mut self) {
+        self.layer_stack.pop();
+    }
+
+    /// Starts a layer whose shapes are not displayed but can be used as the mask of other
+    /// layers once it is finished with `end_mask`.
+    pub fn begin_mask(&mut self) {
+        self.push_layer(1.0, None);
+        self.layers.last_mut().unwrap().is_mask = true;
+    }
+
+    pub fn end_mask(&mut self, mode: MaskMode) -> Mask {
+        let layer = self.layer_stack.pop().expect("end_mask without begin_mask");
+        Mask { layer, mode }
+    }
+
+    /// Casts `shadow` behind the current layer when it is composited.
+    pub fn set_shadow(&mut self, shadow: Shadow) {
+        let layer = self.current_layer().expect("set_shadow outside of a layer");
+        self.layers[layer].shadow = Some(shadow);
+    }
+
+    /// Blurs the contents of the current layer with a gaussian of standard deviation `sigma`
+    /// (in physical pixels) before it is composited. Sigmas above `MAX_BLUR_SIGMA` are
+    /// clamped to it.
+    pub fn set_blur(&mut self, sigma: f32) {
+        let layer = self.current_layer().expect("set_blur outside of a layer");
+        self.layers[layer].blur = sigma;
+    }
+
+    pub fn current_layer(&self) -> Option<usize> {
+        self.layer_stack.last().copied()
+    }
+
+    /// Returns the shapes and direct child layers of `layer` (`None` for the canvas itself)
+    /// in drawing order: by z index, and in the order they were added for equal ones.
+    pub fn layer_items(&self, layer: Option<usize>) -> Vec<LayerItem> {
+        self.all_layer_items().get(layer).to_vec()
+    }
+
+    /// Returns the items of the canvas and of every layer, see `layer_items`, collected in
+    /// one pass over the shapes.
+    pub fn all_layer_items(&self) -> LayerItems {
+        let mut items = LayerItems {
+            root: vec![],
+            layers: vec![vec![]; self.layers.len()],
+        };
+        for index in self.added_order() {
+            if self.sequence[index] == REMOVED {
+                break;
+            }
+            let tessellate = &self.tessellates[index];
+            items
+                .get_mut(tessellate.layer)
+                .push(LayerItem::Shape(index));
+            // The shapes of a layer are added one after the other, so a layer only has to be
+            // compared against the previous item of its parent. When it is already there, so
+            // are its ancestors.
+            let mut child = tessellate.layer;
+            while let Some(layer) = child {
+                let parent = self.layers[layer].parent;
+                let siblings = items.get_mut(parent);
+                if siblings.last() == Some(&LayerItem::Layer(layer)) {
+                    break;
+                }
+                siblings.push(LayerItem::Layer(layer));
+                child = parent;
+            }
+        }
+        let z_index = |item: &LayerItem| match *item {
+            LayerItem::Shape(shape) => self.tessellates[shape].z_index,
+            LayerItem::Layer(layer) => self.layers[layer].z_index,
+        };
+        items.root.sort_by_key(z_index);
+        for layer_items in &mut items.layers {
+            layer_items.sort_by_key(z_index);
+        }
+        items
+    }
+
+    /// Returns all shapes, including those of mask layers, in the order they are painted.
+    pub fn paint_order(&self) -> Vec<usize> {
+        // Without z indices shapes are painted in the order they were added, since the
+        // shapes of a layer are added one after the other.
+        let unordered = self.tessellates.iter().all(|shape| shape.z_index == 0)
+            && self.layers.iter().all(|layer| layer.z_index == 0);
+        if unordered {
+            return self.added_order();
+        }
+        let mut shapes: Vec<usize> = (0..self.tessellates.len()).collect();
+        shapes.sort_by_cached_key(|&shape| self.paint_key(shape));
+        shapes
+    }
+
+    /// Returns all shapes in the order they were added, removed ones last. This is the
+    /// order of their ids until removed ids are reused, which the sort is quick for.
+    fn added_order(&self) -> Vec<usize> {
+        let mut shapes: Vec<usize> = (0..self.tessellates.len()).collect();
+        shapes.sort_by_key(|&shape| self.sequence[shape]);
+        shapes
+    }
+
+    /// Orders shapes like `layer_items` does on every level of the layer tree: each layer
+    /// from the outermost one down, then the shape itself, by z index and then position.
+    fn paint_key(&self, shape: usize) -> Vec<(i32, u64)> {
+        let tessellate = &self.tessellates[shape];
+        let sequence = self.sequence[shape];
+        let mut key = vec![(tessellate.z_index, sequence)];
+        let mut next = tessellate.layer;
+        while let Some(layer) = next {
+            let first_shape = self.layer_first_shapes[layer].unwrap_or(sequence);
+            key.push((self.layers[layer].z_index, first_shape));
+            next = self.layers[layer].parent;
+        }
+        key.reverse();
+        key
+    }
+
+    /// Moves the shape `id` in front of or behind the others, see `Tessellate::z_index`.
+    pub fn set_z_index(&mut self, id: ShapeId, z_index: i32) {
+        let tessellate = &mut self.tessellates[id.0];
+        tessellate.z_index = z_index;
+        tessellate.version = next_version();
+    }
+
+    /// Removes the shape `id`. The ids of the other shapes stay the same, but `id` is given
+    /// to the next shape that is added.
+    pub fn remove(&mut self, id: ShapeId) {
+        if self.sequence[id.0] == REMOVED {
+            return;
+        }
+        self.unindex(id);
+        self.sequence[id.0] = REMOVED;
+        self.free_shapes.push(id.0);
+        let tessellate = &mut self.tessellates[id.0];
+        tessellate.vertices.clear();
+        tessellate.indices.clear();
+        tessellate.path = Path::new();
+        tessellate.texture = None;
+        tessellate.bounds = Box2D::zero();
+        tessellate.version = next_version();
+    }
+
+    /// Changes the color of the shape `id` without tessellating it again. Textures are
+    /// multiplied with it.
+    pub fn set_color(&mut self, id: ShapeId, color: [f32; 4]) {
+        let tessellate = &mut self.tessellates[id.0];
+        tessellate.color = color;
+        for vertex in &mut tessellate.vertices {
+            vertex.color = color;
+        }
+        tessellate.version = next_version();
+    }
+
+    /// Draws the shape `id` with `material`, see `MaterialPipeline::register`, or with its
+    /// color again for `None`.
+    pub fn set_material(&mut self, id: ShapeId, material: Option<MaterialId>) {
+        let tessellate = &mut self.tessellates[id.0];
+        tessellate.material = material;
+        tessellate.version = next_version();
+    }
+
+    /// Replaces the transform of the shape `id`, which is tessellated again.
+    pub fn set_transform(&mut self, id: ShapeId, transform: Transform) {
+        self.tessellates[id.0].transform = transform;
+        self.retessellate(id);
+    }
+
+    /// Replaces the path of the shape `id`, which is tessellated again.
+    pub fn set_path(&mut self, id: ShapeId, path: Path) {
+        self.tessellates[id.0].path = path;
+        self.retessellate(id);
+    }
+
+    /// Draws `texture` stretched over `rect`, which is given in canvas coordinates before
+    /// the current transform. The shape is white, see `set_color` to tint it.
+    pub fn draw_texture(&mut self, texture: &TextureHandle, rect: Box2D) -> ShapeId {
+        let uv = Box2D::new(Point::new(0.0, 0.0), Point::new(1.0, 1.0));
+        self.draw_texture_region(texture, uv, rect)
+    }
+
+    /// Like `draw_texture`, but only draws the part `uv` of the texture, in texture
+    /// coordinates from 0 to 1 with y pointing down. Consecutive shapes drawn from the same
+    /// texture, like images of a `TextureAtlas`, are drawn together.
+    pub fn draw_texture_region(
+        &mut self,
+        texture: &TextureHandle,
+        uv: Box2D,
+        rect: Box2D,
+    ) -> ShapeId {
+        let fill = TextureFill {
+            texture: texture.clone(),
+            rect,
+            uv,
+            mapping: None,
+            colormap: None,
+            nan_color: [0.0; 4],
+            sprites: vec![],
+        };
+        self.push_texture_fill(fill)
+    }
+
+    /// Draws a texture created with `Texture::from_data`, whose values are turned into
+    /// colors by `mapping`.
+    pub fn draw_texture_mapped(
+        &mut self,
+        texture: &TextureHandle,
+        mapping: ValueMapping,
+        rect: Box2D,
+    ) -> ShapeId {
+        let fill = TextureFill {
+            texture: texture.clone(),
+            rect,
+            uv: Box2D::new(Point::new(0.0, 0.0), Point::new(1.0, 1.0)),
+            mapping: Some(mapping),
+            colormap: None,
+            nan_color: [0.0; 4],
+            sprites: vec![],
+        };
+        self.push_texture_fill(fill)
+    }
+
+    /// Draws the first channel of a data texture, like one uploaded with
+    /// `Texture::from_scalars`, through the colormap of `heatmap`.
+    pub fn draw_heatmap(
+        &mut self,
+        data: &TextureHandle,
+        heatmap: &Heatmap,
+        rect: Box2D,
+    ) -> ShapeId {
+        let fill = TextureFill {
+            texture: data.clone(),
+            rect,
+            uv: Box2D::new(Point::new(0.0, 0.0), Point::new(1.0, 1.0)),
+            mapping: Some(ValueMapping::gray(heatmap.min, heatmap.max)),
+            colormap: Some(heatmap.colormap.clone()),
+            nan_color: heatmap.nan_color,
+            sprites: vec![],
+        };
+        self.push_texture_fill(fill)
+    }
+
+    /// Draws `texture` over `rect` like a resizable panel: the corners, `insets` pixels of
+    /// the texture large, keep their size while the edges and the center stretch. A texture
+    /// pixel takes one canvas unit, which suits a pixel transform, see `draw_nine_slice_scaled`.
+    pub fn draw_nine_slice(
+        &mut self,
+        texture: &TextureHandle,
+        insets: Insets,
+        rect: Box2D,
+    ) -> ShapeId {
+        self.draw_nine_slice_scaled(texture, insets, 1.0, rect)
+    }
+
+    /// Like `draw_nine_slice`, with a texture pixel taking `scale` canvas units. Borders
+    /// wider than `rect` are shrunk to fit it.
+    pub fn draw_nine_slice_scaled(
+        &mut self,
+        texture: &TextureHandle,
+        insets: Insets,
+        scale: f32,
+        rect: Box2D,
+    ) -> ShapeId {
+        let (width, height) = texture.size();
+        let (width, height) = (width.max(1) as f32, height.max(1) as f32);
+        let fit = |start: f32, end: f32, length: f32| {
+            let shrink = (length / ((start + end) * scale)).min(1.0);
+            (start * scale * shrink, end * scale * shrink)
+        };
+        let (left, right) = fit(insets.left, insets.right, rect.width());
+        let (top, bottom) = fit(insets.top, insets.bottom, rect.height());
+        // Columns from left to right and rows from top to bottom, in canvas units and in
+        // texture coordinates.
+        let xs = [
+            rect.min.x,
+            rect.min.x + left,
+            rect.max.x - right,
+            rect.max.x,
+        ];
+        let ys = [
+            rect.max.y,
+            rect.max.y - top,
+            rect.min.y + bottom,
+            rect.min.y,
+        ];
+        let us = [0.0, insets.left / width, 1.0 - insets.right / width, 1.0];
+        let vs = [0.0, insets.top / height, 1.0 - insets.bottom / height, 1.0];
+
+        let mut batch = SpriteBatch::new(texture);
+        for row in 0..3 {
+            for column in 0..3 {
+                let (x0, x1, y0, y1) = (xs[column], xs[column + 1], ys[row + 1], ys[row]);
+                if x1 <= x0 || y1 <= y0 {
+                    continue;
+                }
+                let mut sprite = Sprite::new((x0 + x1) / 2.0, (y0 + y1) / 2.0, x1 - x0, y1 - y0);
+                sprite.uv = Box2D::new(
+                    Point::new(us[column], vs[row]),
+                    Point::new(us[column + 1], vs[row + 1]),
+                );
+                batch.push(sprite);
+            }
+        }
+        self.draw_sprites(&batch)
+    }
+
+    /// Draws the sprites of `batch` as one shape, which is hit by points on any of its
+    /// sprites.
+    pub fn draw_sprites(&mut self, batch: &SpriteBatch) -> ShapeId {
+        let fill = TextureFill {
+            texture: batch.texture.clone(),
+            rect: batch.bounds(),
+            uv: Box2D::new(Point::new(0.0, 0.0), Point::new(1.0, 1.0)),
+            mapping: None,
+            colormap: None,
+            nan_color: [0.0; 4],
+            sprites: batch.sprites.clone(),
+        };
+        self.push_texture_fill(fill)
+    }
+
+    /// Replaces the sprites of the shape `id`, drawn with `draw_sprites`, keeping its color,
+    /// transform and place in the paint order.
+    pub fn set_sprites(&mut self, id: ShapeId, batch: &SpriteBatch) {
+        let tessellate = &mut self.tessellates[id.0];
+        let Some(fill) = &mut tessellate.texture else {
+            return;
+        };
+        fill.texture = batch.texture.clone();
+        fill.rect = batch.bounds();
+        fill.sprites.clone_from(&batch.sprites);
+        tessellate.path = rect_path(&fill.rect);
+        self.retessellate(id);
+    }
+
+    /// Adds a shape covering the rectangle of `fill`, whose vertices only serve for the
+    /// bounds, see `fill_quad`.
+    fn push_texture_fill(&mut self, fill: TextureFill) -> ShapeId {
+        let color = [1.0; 4];
+        let buffers = fill_quad(&fill.rect, &self.current_transform(), color);
+        let path = rect_path(&fill.rect);
+        let mut tessellate = to_tessellate(buffers, self, path, PathStyle::Fill, color);
+        tessellate.texture = Some(fill);
+        self.push_tessellate(tessellate)
+    }
+
+    /// Tessellates `path`, or takes its geometry from the cache, and places it with
+    /// `transform` in `color`.
+    fn tessellate(
+        &mut self,
+        path: &Path,
+        style: PathStyle,
+        transform: &Transform,
+        color: [f32; 4],
+    ) -> VertexBuffers<TessellateVertex, u32> {
+        let options = TessellateOptions {
+            style,
+            feather: self.feather,
+            tolerance: self.tolerance,
+        };
+        let geometry = self
+            .cache
+            .get_or_insert_with(path, options, || tessellate_path(path, &options));
+        instance(&geometry, transform, color)
+    }
+
+    fn retessellate(&mut self, id: ShapeId) {
+        self.unindex(id);
+        let tessellate = &self.tessellates[id.0];
+        if let Some(fill) = &tessellate.texture {
+            let buffers = fill_quad(&fill.rect, &tessellate.transform, tessellate.color);
+            self.set_buffers(id, buffers);
+            return;
+        }
+        if self.deferred {
+            self.pending.push(id.0);
+            return;
+        }
+        let tessellate = &self.tessellates[id.0];
+        let (path, style, transform, color) = (
+            tessellate.path.clone(),
+            tessellate.style,
+            tessellate.transform,
+            tessellate.color,
+        );
+        let buffers = self.tessellate(&path, style, &transform, color);
+        self.set_buffers(id, buffers);
+    }
+
+    fn set_buffers(&mut self, id: ShapeId, buffers: VertexBuffers<TessellateVertex, u32>) {
+        let tessellate = &mut self.tessellates[id.0];
+        tessellate.bounds = vertex_bounds(&buffers.vertices);
+        tessellate.vertices = buffers.vertices;
+        tessellate.indices = buffers.indices;
+        tessellate.version = next_version();
+        self.index_shape(id);
+    }
+
+    /// Queues the shape `id` for the spatial index, see `index_pending`.
+    fn index_shape(&mut self, id: ShapeId) {
+        self.unindexed.push(id.0);
+    }
+
+    /// Removes the shape `id` from the spatial index. Unindexed shapes are left alone, the
+    /// current bounds are looked up when they are indexed or queried.
+    fn unindex(&mut self, id: ShapeId) {
+        let bounds = self.tessellates[id.0].bounds;
+        self.index
+            .remove(&GeomWithData::new(to_rectangle(&bounds), id.0));
+    }
+
+    /// The shapes whose bounds intersect `envelope`, in no particular order. Shapes that
+    /// are not indexed yet may be listed more than once.
+    fn locate(&self, envelope: AABB<[f32; 2]>) -> impl Iterator<Item = usize> + '_ {
+        let unindexed = self.unindexed.iter().copied().filter(move |&index| {
+            let tessellate = &self.tessellates[index];
+            !tessellate.vertices.is_empty() && to_aabb(&tessellate.bounds).intersects(&envelope)
+        });
+        self.index
+            .locate_in_envelope_intersecting(&envelope)
+            .map(|entry| entry.data)
+            .chain(unindexed)
+    }
+
+    /// Returns the topmost visible shape under `point`, in canvas coordinates.
+    pub fn hit_test(&self, point: [f32; 2]) -> Option<ShapeId> {
+        self.hit_test_with_tolerance(point, 0.0)
+    }
+
+    /// Like `hit_test`, but also hits shapes whose outline is within `tolerance` canvas
+    /// units of `point`, which makes thin lines easier to pick.
+    pub fn hit_test_with_tolerance(&self, point: [f32; 2], tolerance: f32) -> Option<ShapeId> {
+        let [x, y] = point;
+        let envelope = AABB::from_corners(
+            [x - tolerance, y - tolerance],
+            [x + tolerance, y + tolerance],
+        );
+        let mut candidates: Vec<usize> = self.locate(envelope).collect();
+        // The last shape painted is on top.
+        candidates.sort_by_cached_key(|&shape| self.paint_key(shape));
+        candidates.dedup();
+        candidates
+            .into_iter()
+            .rev()
+            .find(|&index| {
+                let tessellate = &self.tessellates[index];
+                !self.is_in_mask(tessellate.layer)
+                    && tessellate.contains(point, tolerance)
+                    && self
+                        .clip_chain(tessellate.clip)
+                        .into_iter()
+                        .all(|clip| self.clips[clip].tessellate.contains(point, 0.0))
+            })
+            .map(ShapeId)
+    }
+
+    /// Returns the visible shapes whose bounding box intersects `rect` (in canvas
+    /// coordinates), in painting order.
+    pub fn shapes_in_rect(&self, rect: Box2D) -> Vec<ShapeId> {
+        let mut shapes: Vec<usize> = self
+            .locate(to_aabb(&rect))
+            .filter(|&index| !self.is_in_mask(self.tessellates[index].layer))
+            .collect();
+        shapes.sort_by_cached_key(|&shape| self.paint_key(shape));
+        shapes.dedup();
+        shapes.into_iter().map(ShapeId).collect()
+    }
+
+    /// Returns the shapes that can end up on screen, in painting order: those intersecting
+    /// `viewport` (in canvas coordinates) and their scissor rect, which is given in pixels
+    /// of a target of `size`. Shapes in layers with a shadow or blur are always kept since
+    /// those effects reach beyond their bounds.
+    pub fn visible_shapes(&self, viewport: Box2D, size: (u32, u32)) -> Vec<usize> {
+        let mut visible = vec![false; self.tessellates.len()];
+        for index in self.locate(to_aabb(&viewport)) {
+            let tessellate = &self.tessellates[index];
+            visible[index] = tessellate
+                .scissor
+                .is_none_or(|scissor| tessellate.bounds.intersects(&scissor.to_canvas(size)));
+        }
+        for (index, tessellate) in self.tessellates.iter().enumerate() {
+            if !visible[index] && self.has_effects(tessellate.layer) {
+                visible[index] = true;
+            }
+        }
+        // Uploading in painting order lets the renderer batch neighbouring shapes.
+        self.paint_order()
+            .into_iter()
+            .filter(|&index| visible[index])
+            .collect()
+    }
+
+    fn has_effects(&self, layer: Option<usize>) -> bool {
+        let mut next = layer;
+        while let Some(index) = next {
+            if self.layers[index].shadow.is_some() || self.layers[index].blur > 0.0 {
+                return true;
+            }
+            next = self.layers[index].parent;
+        }
+        false
+    }
+
+    /// Whether `layer` or one of its ancestors is a mask, whose shapes are never displayed.
+    fn is_in_mask(&self, layer: Option<usize>) -> bool {
+        let mut next = layer;
+        while let Some(index) = next {
+            if self.layers[index].is_mask {
+                return true;
+            }
+            next = self.layers[index].parent;
         }
+        false
     }
 }
 
+impl Default for Canvas {
+    fn default() -> Canvas {
+        Canvas::new()
+    }
+}
+
+/// Identifies a shape by its index in `Canvas::tessellates`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ShapeId(pub usize);
+
 #[derive(Debug)]
 pub struct Tessellate {
     pub vertices: Vec<TessellateVertex>,
-    pub indices: Vec<u16>, 
+    pub indices: Vec<u32>,
+    pub clip: Option<usize>,
+    pub scissor: Option<ScissorRect>,
+    pub layer: Option<usize>,
+    /// The bounds of `vertices` in canvas coordinates.
+    pub bounds: Box2D,
+    /// The path the vertices were tessellated from, before `transform` is applied.
+    pub path: Path,
+    pub style: PathStyle,
+    pub transform: Transform,
+    pub color: [f32; 4],
+    /// Shapes with a higher z index are painted on top of the other items of their layer,
+    /// those with equal ones in the order they were added.
+    pub z_index: i32,
+    /// Changes whenever the shape changes, so renderers know what to upload again.
+    pub version: u64,
+    /// Draws a texture instead of the vertices, which are still used for the bounds.
+    pub texture: Option<TextureFill>,
+    /// Colors the vertices with a material instead of `color`, which the material gets as
+    /// input. Ignored for shapes filled with a texture.
+    pub material: Option<MaterialId>,
+}
+
+impl Tessellate {
+    /// Whether `point` (in canvas coordinates) is covered by the shape, or within
+    /// `tolerance` canvas units of its outline.
+    pub fn contains(&self, point: [f32; 2], tolerance: f32) -> bool {
+        let Some(inverse) = self.transform.inverse() else {
+            return false;
+        };
+        let point = inverse.transform_point(Point::new(point[0], point[1]));
+        // Tolerances are given in canvas units, the path is tested in its own space.
+        let scale = self.transform.determinant().abs().sqrt();
+        let tolerance = tolerance / scale;
+        // Sprite batches are hit on their sprites, not in the gaps between them.
+        if let Some(fill) = &self.texture {
+            if !fill.sprites.is_empty() {
+                return fill
+                    .sprites
+                    .iter()
+                    .any(|sprite| sprite.contains(point, tolerance));
+            }
+        }
+        match self.style {
+            PathStyle::Fill => {
+                hit_test_path(
+                    &point,
+                    self.path.iter(),
+                    FillRule::EvenOdd,
+                    HIT_TEST_FLATTENING,
+                ) || (tolerance > 0.0 && distance_to_outline(&self.path, point) <= tolerance)
+            }
+            PathStyle::Stroke { width } => {
+                distance_to_outline(&self.path, point) <= width / 2.0 + tolerance
+            }
+        }
+    }
+}
+
+/// A texture stretched over a rectangle, drawn by the `TexturePipeline`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextureFill {
+    pub texture: TextureHandle,
+    /// Where the texture is drawn, before the transform of the shape is applied.
+    pub rect: Box2D,
+    /// The part of the texture that is drawn, in texture coordinates from 0 to 1 with y
+    /// pointing down.
+    pub uv: Box2D,
+    /// Turns the values of data textures into colors.
+    pub mapping: Option<ValueMapping>,
+    /// Looks the first channel up in a colormap after `mapping` brought it from 0 to 1.
+    pub colormap: Option<Colormap>,
+    /// Drawn instead of NaN values when there is a colormap.
+    pub nan_color: [f32; 4],
+    /// When there are sprites, the texture is drawn once for each of them instead of over
+    /// `rect`, which then bounds them.
+    pub sprites: Vec<Sprite>,
+}
+
+/// How a `Tessellate` covers its path.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PathStyle {
+    Fill,
+    Stroke { width: f32 },
+}
+
+fn to_aabb(rect: &Box2D) -> AABB<[f32; 2]> {
+    AABB::from_corners([rect.min.x, rect.min.y], [rect.max.x, rect.max.y])
+}
+
+fn to_rectangle(rect: &Box2D) -> Rectangle<[f32; 2]> {
+    Rectangle::from_aabb(to_aabb(rect))
+}
+
+fn distance_to_outline(path: &Path, point: Point<f32>) -> f32 {
+    path.iter()
+        .flattened(HIT_TEST_FLATTENING)
+        .filter_map(|event| match event {
+            PathEvent::Line { from, to } => Some(LineSegment { from, to }),
+            PathEvent::End {
+                last,
+                first,
+                close: true,
+            } => Some(LineSegment {
+                from: last,
+                to: first,
+            }),
+            _ => None,
+        })
+        .map(|segment| segment.distance_to_point(point))
+        .fold(f32::INFINITY, f32::min)
+}
+
+/// A filled path that is written to the stencil buffer and masks every shape drawn while it
+/// is on the clip stack.
+#[derive(Debug)]
+pub struct Clip {
+    pub tessellate: Tessellate,
+    pub parent: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Layer {
+    pub opacity: f32,
+    pub mask: Option<Mask>,
+    pub parent: Option<usize>,
+    pub is_mask: bool,
+    pub shadow: Option<Shadow>,
+    pub blur: f32,
+    /// Layers with a higher z index are composited on top of the other items of their
+    /// parent, see `Tessellate::z_index`.
+    pub z_index: i32,
+}
+
+/// A blurred, tinted copy of a layer's coverage drawn underneath it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Shadow {
+    /// In physical pixels, with y pointing down.
+    pub offset: [f32; 2],
+    /// Standard deviation of the gaussian blur in physical pixels, at most
+    /// `MAX_BLUR_SIGMA`.
+    pub blur: f32,
+    pub color: [f32; 4],
+}
+
+impl Shadow {
+    pub fn new(offset: [f32; 2], blur: f32, color: [f32; 4]) -> Shadow {
+        Shadow {
+            offset,
+            blur,
+            color,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaskMode {
+    /// Uses the alpha of the mask layer as coverage.
+    Alpha,
+    /// Uses the luminance of the mask layer, multiplied by its alpha, as coverage.
+    Luminance,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mask {
+    pub layer: usize,
+    pub mode: MaskMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerItem {
+    Shape(usize),
+    Layer(usize),
+}
+
+/// The items of the canvas and of each of its layers, see `Canvas::all_layer_items`.
+#[derive(Debug, Clone, Default)]
+pub struct LayerItems {
+    pub root: Vec<LayerItem>,
+    /// Indexed like `Canvas::layers`.
+    pub layers: Vec<Vec<LayerItem>>,
+}
+
+impl LayerItems {
+    /// The items of `layer`, or of the canvas itself for `None`.
+    pub fn get(&self, layer: Option<usize>) -> &[LayerItem] {
+        match layer {
+            Some(layer) => &self.layers[layer],
+            None => &self.root,
+        }
+    }
+
+    fn get_mut(&mut self, layer: Option<usize>) -> &mut Vec<LayerItem> {
+        match layer {
+            Some(layer) => &mut self.layers[layer],
+            None => &mut self.root,
+        }
+    }
+}
+
+/// Distances from the edges of a rectangle inwards, see `Canvas::draw_nine_slice`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Insets {
+    pub left: f32,
+    pub right: f32,
+    pub top: f32,
+    pub bottom: f32,
+}
+
+impl Insets {
+    pub fn new(left: f32, right: f32, top: f32, bottom: f32) -> Insets {
+        Insets {
+            left,
+            right,
+            top,
+            bottom,
+        }
+    }
+
+    /// The same distance from every edge.
+    pub fn uniform(inset: f32) -> Insets {
+        Insets::new(inset, inset, inset, inset)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScissorRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ScissorRect {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> ScissorRect {
+        ScissorRect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// The rect in canvas coordinates on a target of `size`.
+    pub fn to_canvas(&self, size: (u32, u32)) -> Box2D {
+        let (width, height) = (size.0.max(1) as f32, size.1.max(1) as f32);
+        let x = |x: u32| x as f32 / width * 2.0 - 1.0;
+        let y = |y: u32| 1.0 - y as f32 / height * 2.0;
+        Box2D::new(
+            Point::new(x(self.x), y(self.y.saturating_add(self.height))),
+            Point::new(x(self.x.saturating_add(self.width)), y(self.y)),
+        )
+    }
+
+    pub fn intersect(&self, other: &ScissorRect) -> ScissorRect {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = self.x.saturating_add(self.width);
+        let right = right.min(other.x.saturating_add(other.width));
+        let bottom = self.y.saturating_add(self.height);
+        let bottom = bottom.min(other.y.saturating_add(other.height));
+        ScissorRect {
+            x,
+            y,
+            width: right.saturating_sub(x),
+            height: bottom.saturating_sub(y),
+        }
+    }
 }
 
 pub struct Line {
     builder: Builder,
     color: [f32; 4],
+    shadow: Option<Shadow>,
+    blur: f32,
+    z_index: i32,
+    material: Option<MaterialId>,
 }
 
 impl Line {
@@ -38,6 +1121,10 @@ impl Line {
         Line {
             builder,
             color,
+            shadow: None,
+            blur: 0.0,
+            z_index: 0,
+            material: None,
         }
     }
 
@@ -45,33 +1132,259 @@ impl Line {
         self.builder.line_to(Point::new(x, y));
     }
 
-    pub fn end(mut self, canvas: &mut Canvas) {
+    /// Draws the line with a drop shadow, see `Canvas::set_shadow`.
+    pub fn shadow(&mut self, shadow: Shadow) {
+        self.shadow = Some(shadow);
+    }
+
+    /// Draws the line blurred, see `Canvas::set_blur`.
+    pub fn blur(&mut self, sigma: f32) {
+        self.blur = sigma;
+    }
+
+    /// Paints the line in front of or behind the other shapes, see `Tessellate::z_index`.
+    pub fn z_index(&mut self, z_index: i32) {
+        self.z_index = z_index;
+    }
+
+    /// Draws the line with a material, see `Canvas::set_material`.
+    pub fn material(&mut self, material: MaterialId) {
+        self.material = Some(material);
+    }
+
+    pub fn end(mut self, canvas: &mut Canvas) -> ShapeId {
         self.builder.end(true);
         let path = self.builder.build();
-        let mut buffers: VertexBuffers<Point<f32>, u16> = VertexBuffers::new();
-        {
-            let mut vertex_builder = simple_builder(&mut buffers);
-            let mut tessellator = StrokeTessellator::new();
-            let stroke_options = StrokeOptions::default().with_line_width(0.01);
-            let _ = tessellator.tessellate(
-                &path,
-                &stroke_options,
-                &mut vertex_builder).unwrap();
-        }
-    
-            let pad = buffers.indices.len() % 4;
-            for _ in 0..pad {
-                buffers.indices.push(buffers.indices.last().unwrap().clone());
-            }
-        canvas.tessellates.push(Tessellate {
-            vertices: buffers.vertices.iter().map(|v| {
+        let style = PathStyle::Stroke { width: LINE_WIDTH };
+        let buffers = if canvas.deferred {
+            VertexBuffers::new()
+        } else {
+            let transform = canvas.current_transform();
+            canvas.tessellate(&path, style, &transform, self.color)
+        };
+
+        // Effects work on whole layers, so the line gets a layer of its own.
+        let has_effects = self.shadow.is_some() || self.blur > 0.0;
+        if has_effects {
+            canvas.push_layer(1.0, None);
+            if let Some(shadow) = self.shadow {
+                canvas.set_shadow(shadow);
+            }
+            canvas.set_blur(self.blur);
+            // The layer takes the place of the line among the items of its parent.
+            let layer = canvas.current_layer().unwrap();
+            canvas.layers[layer].z_index = self.z_index;
+        }
+        let mut tessellate = to_tessellate(buffers, canvas, path, style, self.color);
+        tessellate.z_index = self.z_index;
+        tessellate.material = self.material;
+        let id = canvas.push_tessellate(tessellate);
+        if canvas.deferred {
+            canvas.pending.push(id.0);
+        }
+        if has_effects {
+            canvas.pop_layer();
+        }
+        id
+    }
+
+    /// Replaces the path and color of the shape `id` with this line. The shape keeps its
+    /// place, layer, clip, scissor and transform.
+    pub fn replace(mut self, canvas: &mut Canvas, id: ShapeId) {
+        self.builder.end(true);
+        canvas.tessellates[id.0].color = self.color;
+        canvas.set_path(id, self.builder.build());
+    }
+
+    /// Closes the line and pushes its filled area onto the clip stack of `canvas` until the
+    /// matching `Canvas::pop_clip`.
+    pub fn clip(mut self, canvas: &mut Canvas) {
+        self.builder.end(true);
+        let path = self.builder.build();
+        let transform = canvas.current_transform();
+        let buffers = canvas.tessellate(&path, PathStyle::Fill, &transform, self.color);
+        let clip = Clip {
+            tessellate: to_tessellate(buffers, canvas, path, PathStyle::Fill, self.color),
+            parent: canvas.current_clip(),
+        };
+        canvas.clips.push(clip);
+        canvas.clip_stack.push(canvas.clips.len() - 1);
+    }
+}
+
+/// Tessellates `path` in its own space and without color, see `instance`. Strokes are
+/// widened by the feather fringe on both sides.
+fn tessellate_path(path: &Path, options: &TessellateOptions) -> Geometry {
+    let mut buffers: Geometry = VertexBuffers::new();
+    let uv = path_uv(path);
+    match options.style {
+        PathStyle::Stroke { width } => {
+            // The fringe widens the stroke, the edge attribute runs from -1 to 1 across it
+            // so the fragment shader can fade out the fringe, which starts at 1 - `fringe`
+            // on either side.
+            let fringe = if options.feather > 0.0 {
+                options.feather / (width / 2.0 + options.feather)
+            } else {
+                0.0
+            };
+            let mut vertex_builder = BuffersBuilder::new(&mut buffers, |vertex: StrokeVertex| {
+                let side = match vertex.side() {
+                    Side::Positive => 1.0,
+                    Side::Negative => -1.0,
+                };
+                let position = vertex.position();
                 TessellateVertex {
-                    color: self.color,
-                    position: [v.x, v.y, 0.1],
+                    color: [0.0; 4],
+                    position: [position.x, position.y, 0.1],
+                    edge: [side, fringe],
+                    uv: uv(position),
                 }
-            }).collect(),
-            indices: buffers.indices.to_vec(),
-        });
+            });
+            let stroke_options = StrokeOptions::default()
+                .with_line_width(width + 2.0 * options.feather)
+                .with_tolerance(options.tolerance);
+            StrokeTessellator::new()
+                .tessellate(path, &stroke_options, &mut vertex_builder)
+                .unwrap();
+        }
+        PathStyle::Fill => {
+            let mut vertex_builder = BuffersBuilder::new(&mut buffers, |vertex: FillVertex| {
+                let position = vertex.position();
+                TessellateVertex {
+                    color: [0.0; 4],
+                    position: [position.x, position.y, 0.1],
+                    edge: [0.0; 2],
+                    uv: uv(position),
+                }
+            });
+            let fill_options = FillOptions::default().with_tolerance(options.tolerance);
+            FillTessellator::new()
+                .tessellate_path(path, &fill_options, &mut vertex_builder)
+                .unwrap();
+        }
+    }
+    buffers
+}
+
+/// Maps points to their position inside the bounds of `path`, with v growing downwards
+/// like for textures. Strokes reach a little past the bounds.
+fn path_uv(path: &Path) -> impl Fn(Point<f32>) -> [f32; 2] {
+    let bounds = bounding_box(path.iter());
+    let size = bounds.size();
+    move |point| {
+        [
+            (point.x - bounds.min.x) / size.width.max(f32::EPSILON),
+            (bounds.max.y - point.y) / size.height.max(f32::EPSILON),
+        ]
+    }
+}
+
+/// Places a copy of the cached `geometry` with `transform` in `color`. The vertices are
+/// transformed here rather than in the vertex shader since the bounds, the spatial index
+/// and `Canvas::hit_test` need them in canvas coordinates too.
+fn instance(
+    geometry: &Geometry,
+    transform: &Transform,
+    color: [f32; 4],
+) -> VertexBuffers<TessellateVertex, u32> {
+    let vertices = geometry
+        .vertices
+        .iter()
+        .map(|vertex| {
+            let [x, y, z] = vertex.position;
+            let position = transform.transform_point(Point::new(x, y));
+            TessellateVertex {
+                color,
+                position: [position.x, position.y, z],
+                edge: vertex.edge,
+                uv: vertex.uv,
+            }
+        })
+        .collect();
+    VertexBuffers {
+        vertices,
+        indices: geometry.indices.clone(),
+    }
+}
+
+fn vertex_bounds(vertices: &[TessellateVertex]) -> Box2D {
+    Box2D::from_points(
+        vertices
+            .iter()
+            .map(|vertex| Point::new(vertex.position[0], vertex.position[1])),
+    )
+}
+
+/// The sequence number of removed shapes, which sorts them after all others.
+const REMOVED: u64 = u64::MAX;
+
+/// Returns a version no other `Tessellate` has had before.
+fn next_version() -> u64 {
+    static NEXT_VERSION: AtomicU64 = AtomicU64::new(1);
+    NEXT_VERSION.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The rectangle of a texture fill placed with `transform`. The `TexturePipeline` draws
+/// texture fills, so their vertices only serve for the bounds and culling. They are made
+/// here rather than tessellated, since sprite batches move their rectangle every frame and
+/// would fill the tessellation cache.
+fn fill_quad(
+    rect: &Box2D,
+    transform: &Transform,
+    color: [f32; 4],
+) -> VertexBuffers<TessellateVertex, u32> {
+    let corners = [
+        (rect.min.x, rect.min.y, [0.0, 1.0]),
+        (rect.max.x, rect.min.y, [1.0, 1.0]),
+        (rect.max.x, rect.max.y, [1.0, 0.0]),
+        (rect.min.x, rect.max.y, [0.0, 0.0]),
+    ];
+    let vertices = corners
+        .into_iter()
+        .map(|(x, y, uv)| {
+            let position = transform.transform_point(Point::new(x, y));
+            TessellateVertex {
+                color,
+                position: [position.x, position.y, 0.1],
+                edge: [0.0; 2],
+                uv,
+            }
+        })
+        .collect();
+    VertexBuffers {
+        vertices,
+        indices: vec![0, 1, 2, 0, 2, 3],
+    }
+}
+
+fn rect_path(rect: &Box2D) -> Path {
+    let mut builder = Path::builder();
+    builder.add_rectangle(rect, lyon::path::Winding::Positive);
+    builder.build()
+}
+
+fn to_tessellate(
+    buffers: VertexBuffers<TessellateVertex, u32>,
+    canvas: &Canvas,
+    path: Path,
+    style: PathStyle,
+    color: [f32; 4],
+) -> Tessellate {
+    Tessellate {
+        bounds: vertex_bounds(&buffers.vertices),
+        vertices: buffers.vertices,
+        indices: buffers.indices,
+        clip: canvas.current_clip(),
+        scissor: canvas.current_scissor(),
+        layer: canvas.current_layer(),
+        path,
+        style,
+        transform: canvas.current_transform(),
+        color,
+        z_index: 0,
+        version: next_version(),
+        texture: None,
+        material: None,
     }
 }
 
@@ -79,8 +1392,250 @@ impl Line {
 // pub struct Rect {}
 // pub fn draw_rect(canvas: &mut Canvas, rect: &Rect) {}
 //
-// pub struct Texture {}
-// pub fn draw_texture(canvas: &mut Canvas, texture: &Texture) {}
-//
 // pub struct Text {}
 // pub fn draw_text(canvas: &mut Canvas, text: &Text) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scissor_intersect_overlapping() {
+        let a = ScissorRect::new(0, 0, 100, 50);
+        let b = ScissorRect::new(20, 10, 200, 200);
+        assert_eq!(a.intersect(&b), ScissorRect::new(20, 10, 80, 40));
+        assert_eq!(b.intersect(&a), ScissorRect::new(20, 10, 80, 40));
+    }
+
+    #[test]
+    fn scissor_intersect_disjoint_is_empty() {
+        let a = ScissorRect::new(0, 0, 10, 10);
+        let b = ScissorRect::new(20, 20, 10, 10);
+        let intersection = a.intersect(&b);
+        assert_eq!((intersection.width, intersection.height), (0, 0));
+    }
+
+    #[test]
+    fn scissor_intersect_does_not_overflow() {
+        let huge = ScissorRect::new(10, 10, u32::MAX, u32::MAX);
+        let full = ScissorRect::new(0, 0, 640, 480);
+        assert_eq!(huge.intersect(&full), ScissorRect::new(10, 10, 630, 470));
+    }
+
+    const RED: [f32; 4] = [1.0, 0.0, 0.0, 1.0];
+
+    /// The outline of the square with the given center and half size.
+    fn square(center: [f32; 2], half: f32) -> Line {
+        let [x, y] = center;
+        let mut line = Line::start(x - half, y - half, RED);
+        line.to(x + half, y - half);
+        line.to(x + half, y + half);
+        line.to(x - half, y + half);
+        line
+    }
+
+    #[test]
+    fn hit_test_follows_current_transform() {
+        let mut canvas = Canvas::new();
+        canvas.push_transform(Transform::translation(0.5, 0.0));
+        let id = square([0.0, 0.0], 0.1).end(&mut canvas);
+        canvas.pop_transform();
+
+        // The left edge of the square moved from -0.1 to 0.4.
+        assert_eq!(canvas.hit_test([0.4, 0.0]), Some(id));
+        assert_eq!(canvas.hit_test([-0.1, 0.0]), None);
+    }
+
+    #[test]
+    fn hit_test_follows_set_transform() {
+        let mut canvas = Canvas::new();
+        let id = square([0.0, 0.0], 0.1).end(&mut canvas);
+        canvas.set_transform(id, Transform::scale(2.0, 2.0));
+
+        assert_eq!(canvas.hit_test([0.2, 0.0]), Some(id));
+        assert_eq!(canvas.hit_test([0.1, 0.0]), None);
+        // Tolerances are in canvas units, so scaling the shape does not scale them.
+        assert_eq!(canvas.hit_test_with_tolerance([0.225, 0.0], 0.02), Some(id));
+        assert_eq!(canvas.hit_test_with_tolerance([0.225, 0.0], 0.01), None);
+    }
+
+    #[test]
+    fn hit_test_respects_clips() {
+        let mut canvas = Canvas::new();
+        square([0.0, 0.0], 0.5).clip(&mut canvas);
+        let mut line = Line::start(-0.9, 0.0, RED);
+        line.to(0.9, 0.0);
+        let id = line.end(&mut canvas);
+        canvas.pop_clip();
+
+        assert_eq!(canvas.hit_test([0.0, 0.0]), Some(id));
+        assert_eq!(canvas.hit_test([0.8, 0.0]), None);
+    }
+
+    #[test]
+    fn hit_test_respects_nested_clips_and_transforms() {
+        let mut canvas = Canvas::new();
+        square([0.0, 0.0], 0.5).clip(&mut canvas);
+        // The inner clip is moved to the right, so only x from 0.2 to 0.5 is left.
+        canvas.push_transform(Transform::translation(0.5, 0.0));
+        square([0.0, 0.0], 0.3).clip(&mut canvas);
+        canvas.pop_transform();
+        let mut line = Line::start(-0.9, 0.0, RED);
+        line.to(0.9, 0.0);
+        let id = line.end(&mut canvas);
+        canvas.pop_clip();
+        canvas.pop_clip();
+
+        assert_eq!(canvas.hit_test([0.3, 0.0]), Some(id));
+        assert_eq!(canvas.hit_test([0.0, 0.0]), None);
+        assert_eq!(canvas.hit_test([0.7, 0.0]), None);
+    }
+
+    #[test]
+    fn hit_test_returns_topmost_shape() {
+        let mut canvas = Canvas::new();
+        let mut below = Line::start(-0.5, 0.0, RED);
+        below.to(0.5, 0.0);
+        below.z_index(1);
+        let below = below.end(&mut canvas);
+        let mut above = Line::start(-0.5, 0.0, RED);
+        above.to(0.5, 0.0);
+        let above = above.end(&mut canvas);
+
+        // The higher z index is painted last although it was added first.
+        assert_eq!(canvas.hit_test([0.0, 0.0]), Some(below));
+        canvas.set_z_index(below, 0);
+        assert_eq!(canvas.hit_test([0.0, 0.0]), Some(above));
+    }
+
+    #[test]
+    fn hit_test_skips_masks() {
+        let mut canvas = Canvas::new();
+        canvas.begin_mask();
+        square([0.0, 0.0], 0.1).end(&mut canvas);
+        canvas.end_mask(MaskMode::Alpha);
+
+        assert_eq!(canvas.hit_test([0.1, 0.0]), None);
+    }
+
+    #[test]
+    fn hit_test_finds_shapes_before_and_after_flush() {
+        let mut canvas = Canvas::new();
+        let ids: Vec<ShapeId> = (0..10)
+            .map(|i| square([i as f32 * 0.1 - 0.5, 0.0], 0.02).end(&mut canvas))
+            .collect();
+        assert_eq!(canvas.hit_test([-0.52, 0.0]), Some(ids[0]));
+        canvas.flush();
+        assert_eq!(canvas.hit_test([-0.52, 0.0]), Some(ids[0]));
+
+        canvas.set_transform(ids[0], Transform::translation(0.0, 0.5));
+        assert_eq!(canvas.hit_test([-0.52, 0.0]), None);
+        assert_eq!(canvas.hit_test([-0.52, 0.5]), Some(ids[0]));
+        canvas.remove(ids[1]);
+        assert_eq!(canvas.hit_test([-0.42, 0.0]), None);
+        canvas.flush();
+        assert_eq!(canvas.hit_test([-0.52, 0.5]), Some(ids[0]));
+        assert_eq!(canvas.hit_test([-0.42, 0.0]), None);
+        assert_eq!(
+            canvas
+                .shapes_in_rect(Box2D::new(Point::new(-1.0, -1.0), Point::new(1.0, 1.0)))
+                .len(),
+            9
+        );
+    }
+
+    #[test]
+    fn visible_shapes_respect_viewport_and_scissor() {
+        let mut canvas = Canvas::new();
+        let inside = square([0.0, 0.0], 0.1).end(&mut canvas);
+        let outside = square([2.0, 0.0], 0.1).end(&mut canvas);
+        // The left half of a 100 by 100 target.
+        canvas.push_scissor(ScissorRect::new(0, 0, 50, 100));
+        // Outside of its scissor rect.
+        square([0.5, 0.0], 0.1).end(&mut canvas);
+        let in_scissor = square([-0.5, 0.0], 0.1).end(&mut canvas);
+        canvas.pop_scissor();
+
+        let screen = Box2D::new(Point::new(-1.0, -1.0), Point::new(1.0, 1.0));
+        assert_eq!(
+            canvas.visible_shapes(screen, (100, 100)),
+            vec![inside.0, in_scissor.0]
+        );
+        let wide = Box2D::new(Point::new(-3.0, -1.0), Point::new(3.0, 1.0));
+        assert_eq!(
+            canvas.visible_shapes(wide, (100, 100)),
+            vec![inside.0, outside.0, in_scissor.0]
+        );
+    }
+
+    #[test]
+    fn removed_ids_are_reused_on_top() {
+        let mut canvas = Canvas::new();
+        let first = square([0.0, 0.0], 0.1).end(&mut canvas);
+        let second = square([0.0, 0.0], 0.1).end(&mut canvas);
+        canvas.remove(first);
+        canvas.remove(first);
+        let third = square([0.0, 0.0], 0.1).end(&mut canvas);
+        let fourth = square([0.0, 0.0], 0.1).end(&mut canvas);
+
+        assert_eq!(third, first);
+        assert_eq!(fourth.0, 2);
+        assert_eq!(canvas.paint_order(), vec![second.0, third.0, fourth.0]);
+        assert_eq!(canvas.hit_test([0.1, 0.0]), Some(fourth));
+        canvas.remove(fourth);
+        assert_eq!(canvas.hit_test([0.1, 0.0]), Some(third));
+    }
+
+    #[test]
+    fn reused_ids_keep_layers_in_place() {
+        let mut canvas = Canvas::new();
+        let removed = square([0.0, 0.0], 0.1).end(&mut canvas);
+        canvas.push_layer(0.5, None);
+        let inside = square([0.0, 0.0], 0.1).end(&mut canvas);
+        canvas.pop_layer();
+        canvas.remove(removed);
+        let above = square([0.0, 0.0], 0.1).end(&mut canvas);
+
+        assert_eq!(above, removed);
+        assert_eq!(
+            canvas.layer_items(None),
+            vec![LayerItem::Layer(0), LayerItem::Shape(above.0)]
+        );
+        assert_eq!(
+            canvas.layer_items(Some(0)),
+            vec![LayerItem::Shape(inside.0)]
+        );
+        assert_eq!(canvas.hit_test([0.1, 0.0]), Some(above));
+    }
+
+    #[test]
+    fn z_index_and_material_changes_bump_the_version() {
+        let mut canvas = Canvas::new();
+        let id = square([0.0, 0.0], 0.1).end(&mut canvas);
+        let version = canvas.tessellates[id.0].version;
+        canvas.set_z_index(id, 1);
+        let raised = canvas.tessellates[id.0].version;
+        assert_ne!(raised, version);
+        canvas.set_material(id, None);
+        assert_ne!(canvas.tessellates[id.0].version, raised);
+    }
+
+    #[test]
+    fn sprite_batches_are_hit_on_their_sprites_and_not_cached() {
+        let mut canvas = Canvas::new();
+        let mut batch = SpriteBatch::new(&TextureHandle::detached(4, 4));
+        batch.push(Sprite::new(-0.5, 0.0, 0.2, 0.2));
+        batch.push(Sprite::new(0.5, 0.0, 0.2, 0.2));
+        let id = canvas.draw_sprites(&batch);
+        assert_eq!(canvas.hit_test([-0.5, 0.0]), Some(id));
+        assert_eq!(canvas.hit_test([0.0, 0.0]), None);
+
+        for frame in 0..10 {
+            batch.sprites[1].position.x = frame as f32 * 0.01;
+            canvas.set_sprites(id, &batch);
+        }
+        assert_eq!(canvas.hit_test([0.15, 0.0]), Some(id));
+        assert_eq!(canvas.hit_test([0.3, 0.0]), None);
+        assert!(canvas.cache.is_empty());
+    }
+}
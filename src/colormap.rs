@@ -0,0 +1,153 @@
+use anyhow::{bail, Result};
+use image::{DynamicImage, RgbaImage};
+use wgpu::{Device, Queue};
+
+use crate::store::{TextureHandle, TextureStore};
+use crate::texture::{SamplerOptions, Texture};
+
+/// The number of entries of the lookup textures built from color stops.
+const LUT_SIZE: u32 = 256;
+
+/// Samples of matplotlib's viridis at nine evenly spaced values, in sRGB.
+const VIRIDIS: [[u8; 4]; 9] = [
+    [68, 1, 84, 255],
+    [72, 40, 120, 255],
+    [62, 73, 137, 255],
+    [49, 104, 142, 255],
+    [38, 130, 142, 255],
+    [31, 158, 137, 255],
+    [53, 183, 121, 255],
+    [110, 206, 88, 255],
+    [253, 231, 37, 255],
+];
+
+/// Samples of matplotlib's magma at nine evenly spaced values, in sRGB.
+const MAGMA: [[u8; 4]; 9] = [
+    [0, 0, 4, 255],
+    [28, 16, 68, 255],
+    [79, 18, 123, 255],
+    [129, 37, 129, 255],
+    [181, 54, 122, 255],
+    [229, 80, 100, 255],
+    [251, 135, 97, 255],
+    [254, 194, 135, 255],
+    [252, 253, 191, 255],
+];
+
+/// A one dimensional lookup texture that turns values from 0 to 1 into colors, see
+/// `Canvas::draw_heatmap`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Colormap {
+    pub texture: TextureHandle,
+}
+
+impl Colormap {
+    pub fn viridis(device: &Device, queue: &Queue, textures: &mut TextureStore) -> Colormap {
+        Colormap::preset(device, queue, textures, &VIRIDIS)
+    }
+
+    pub fn magma(device: &Device, queue: &Queue, textures: &mut TextureStore) -> Colormap {
+        Colormap::preset(device, queue, textures, &MAGMA)
+    }
+
+    pub fn grayscale(device: &Device, queue: &Queue, textures: &mut TextureStore) -> Colormap {
+        Colormap::preset(device, queue, textures, &[[0, 0, 0, 255], [255; 4]])
+    }
+
+    fn preset(
+        device: &Device,
+        queue: &Queue,
+        textures: &mut TextureStore,
+        stops: &[[u8; 4]],
+    ) -> Colormap {
+        // Every device supports textures far wider than `LUT_SIZE`.
+        Colormap::from_stops(device, queue, textures, stops).expect("preset stops are valid")
+    }
+
+    /// A colormap blending linearly between `stops`, sRGB colors for evenly spaced values
+    /// from 0 to 1. Fails without stops.
+    pub fn from_stops(
+        device: &Device,
+        queue: &Queue,
+        textures: &mut TextureStore,
+        stops: &[[u8; 4]],
+    ) -> Result<Colormap> {
+        if stops.is_empty() {
+            bail!("a colormap needs at least one color stop");
+        }
+        let lut: Vec<[u8; 4]> = (0..LUT_SIZE)
+            .map(|index| {
+                let position =
+                    index as f32 / (LUT_SIZE - 1) as f32 * (stops.len() - 1).max(1) as f32;
+                let low = (position.floor() as usize).min(stops.len() - 1);
+                let high = (low + 1).min(stops.len() - 1);
+                let t = position - low as f32;
+                let mut color = [0; 4];
+                for channel in 0..4 {
+                    let (a, b) = (stops[low][channel] as f32, stops[high][channel] as f32);
+                    color[channel] = (a + (b - a) * t).round() as u8;
+                }
+                color
+            })
+            .collect();
+        Colormap::from_lut(device, queue, textures, &lut)
+    }
+
+    /// A colormap from a user supplied lookup table of sRGB colors, the first for the
+    /// lowest value and the last for the highest. Values in between are blended. Fails for
+    /// an empty table or one wider than the textures of `device` can be.
+    pub fn from_lut(
+        device: &Device,
+        queue: &Queue,
+        textures: &mut TextureStore,
+        lut: &[[u8; 4]],
+    ) -> Result<Colormap> {
+        let max_len = device.limits().max_texture_dimension_2d;
+        if lut.is_empty() || lut.len() > max_len as usize {
+            bail!(
+                "a colormap lookup table needs 1 to {max_len} colors, got {}",
+                lut.len()
+            );
+        }
+        let image = RgbaImage::from_fn(lut.len() as u32, 1, |x, _| image::Rgba(lut[x as usize]));
+        let sampler = SamplerOptions {
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..SamplerOptions::default()
+        };
+        let texture = Texture::from_image(
+            device,
+            queue,
+            &DynamicImage::ImageRgba8(image),
+            Some("colormap"),
+            &sampler,
+        )?;
+        Ok(Colormap {
+            texture: textures.insert(device, texture),
+        })
+    }
+}
+
+/// How `Canvas::draw_heatmap` colors scalar data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heatmap {
+    pub colormap: Colormap,
+    /// The value drawn with the first color of the colormap, lower ones are clamped.
+    pub min: f32,
+    /// The value drawn with the last color of the colormap, higher ones are clamped.
+    pub max: f32,
+    /// Drawn where the data is NaN.
+    pub nan_color: [f32; 4],
+}
+
+impl Heatmap {
+    /// Colors values from `min` to `max` with `colormap`, leaving NaN values transparent.
+    pub fn new(colormap: Colormap, min: f32, max: f32) -> Heatmap {
+        Heatmap {
+            colormap,
+            min,
+            max,
+            nan_color: [0.0; 4],
+        }
+    }
+}
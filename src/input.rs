@@ -0,0 +1,369 @@
+use std::collections::{HashMap, HashSet};
+
+use winit::dpi::{PhysicalPosition, PhysicalSize};
+use winit::event::{ElementState, Ime, MouseButton, MouseScrollDelta, TouchPhase, WindowEvent};
+use winit::keyboard::{Key, KeyCode, ModifiersState, PhysicalKey};
+
+/// How many logical pixels one line of a line based scroll wheel moves.
+const LINE_HEIGHT: f32 = 20.0;
+
+/// An input event of the window. Positions are in canvas coordinates: x to the right and
+/// y up, from -1 to 1 across the window.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputEvent {
+    CursorMoved {
+        position: [f32; 2],
+    },
+    CursorLeft,
+    MouseDown {
+        button: MouseButton,
+        position: [f32; 2],
+    },
+    MouseUp {
+        button: MouseButton,
+        position: [f32; 2],
+    },
+    /// In logical pixels, positive values scroll right and down.
+    Scroll {
+        delta: [f32; 2],
+    },
+    KeyDown {
+        key: Key,
+        code: Option<KeyCode>,
+        repeat: bool,
+    },
+    KeyUp {
+        key: Key,
+        code: Option<KeyCode>,
+    },
+    /// The text produced by a key press, already taking the modifiers into account, or
+    /// committed by an input method once the app allows them with
+    /// `Window::set_ime_allowed`.
+    Text(String),
+    ModifiersChanged(ModifiersState),
+    Touch {
+        id: u64,
+        phase: TouchPhase,
+        position: [f32; 2],
+    },
+}
+
+/// The polled input state. Everything named `pressed`, `released`, `scroll` or `text` only
+/// covers the events since the previous frame.
+#[derive(Debug, Default)]
+pub struct Input {
+    cursor: Option<[f32; 2]>,
+    logical_cursor: Option<[f32; 2]>,
+    buttons_down: HashSet<MouseButton>,
+    buttons_pressed: HashSet<MouseButton>,
+    buttons_released: HashSet<MouseButton>,
+    keys_down: HashSet<KeyCode>,
+    keys_pressed: HashSet<KeyCode>,
+    keys_released: HashSet<KeyCode>,
+    modifiers: ModifiersState,
+    scroll: [f32; 2],
+    text: String,
+    touches: HashMap<u64, [f32; 2]>,
+}
+
+impl Input {
+    pub fn new() -> Input {
+        Input::default()
+    }
+
+    /// The cursor in canvas coordinates, `None` while it is outside of the window.
+    pub fn cursor(&self) -> Option<[f32; 2]> {
+        self.cursor
+    }
+
+    /// The cursor in logical pixels from the top left corner of the window.
+    pub fn logical_cursor(&self) -> Option<[f32; 2]> {
+        self.logical_cursor
+    }
+
+    pub fn is_button_down(&self, button: MouseButton) -> bool {
+        self.buttons_down.contains(&button)
+    }
+
+    pub fn was_button_pressed(&self, button: MouseButton) -> bool {
+        self.buttons_pressed.contains(&button)
+    }
+
+    pub fn was_button_released(&self, button: MouseButton) -> bool {
+        self.buttons_released.contains(&button)
+    }
+
+    pub fn is_key_down(&self, code: KeyCode) -> bool {
+        self.keys_down.contains(&code)
+    }
+
+    pub fn was_key_pressed(&self, code: KeyCode) -> bool {
+        self.keys_pressed.contains(&code)
+    }
+
+    pub fn was_key_released(&self, code: KeyCode) -> bool {
+        self.keys_released.contains(&code)
+    }
+
+    pub fn modifiers(&self) -> ModifiersState {
+        self.modifiers
+    }
+
+    /// In logical pixels, positive values scroll right and down.
+    pub fn scroll_delta(&self) -> [f32; 2] {
+        self.scroll
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The active touches by id, in canvas coordinates.
+    pub fn touches(&self) -> &HashMap<u64, [f32; 2]> {
+        &self.touches
+    }
+
+    /// Updates the state from a window event of a window with the given size and scale
+    /// factor and returns the corresponding input events.
+    pub fn handle(
+        &mut self,
+        event: &WindowEvent,
+        size: PhysicalSize<u32>,
+        scale_factor: f64,
+    ) -> Vec<InputEvent> {
+        match event {
+            WindowEvent::CursorMoved { position, .. } => {
+                let canvas_position = to_canvas(*position, size);
+                let logical = position.to_logical::<f32>(scale_factor);
+                self.cursor = Some(canvas_position);
+                self.logical_cursor = Some([logical.x, logical.y]);
+                vec![InputEvent::CursorMoved {
+                    position: canvas_position,
+                }]
+            }
+            WindowEvent::CursorLeft { .. } => {
+                self.cursor = None;
+                self.logical_cursor = None;
+                vec![InputEvent::CursorLeft]
+            }
+            WindowEvent::MouseInput { state, button, .. } => {
+                let position = self.cursor.unwrap_or_default();
+                match state {
+                    ElementState::Pressed => {
+                        self.buttons_down.insert(*button);
+                        self.buttons_pressed.insert(*button);
+                        vec![InputEvent::MouseDown {
+                            button: *button,
+                            position,
+                        }]
+                    }
+                    ElementState::Released => {
+                        self.buttons_down.remove(button);
+                        self.buttons_released.insert(*button);
+                        vec![InputEvent::MouseUp {
+                            button: *button,
+                            position,
+                        }]
+                    }
+                }
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                // winit reports positive values for scrolling left and up.
+                let delta = match delta {
+                    MouseScrollDelta::LineDelta(x, y) => [-x * LINE_HEIGHT, -y * LINE_HEIGHT],
+                    MouseScrollDelta::PixelDelta(position) => {
+                        let logical = position.to_logical::<f32>(scale_factor);
+                        [-logical.x, -logical.y]
+                    }
+                };
+                self.scroll[0] += delta[0];
+                self.scroll[1] += delta[1];
+                vec![InputEvent::Scroll { delta }]
+            }
+            WindowEvent::KeyboardInput { event, .. } => {
+                let code = match event.physical_key {
+                    PhysicalKey::Code(code) => Some(code),
+                    PhysicalKey::Unidentified(_) => None,
+                };
+                match event.state {
+                    ElementState::Pressed => {
+                        if let Some(code) = code {
+                            self.keys_down.insert(code);
+                            self.keys_pressed.insert(code);
+                        }
+                        let mut events = vec![InputEvent::KeyDown {
+                            key: event.logical_key.clone(),
+                            code,
+                            repeat: event.repeat,
+                        }];
+                        if let Some(text) = &event.text {
+                            self.text.push_str(text);
+                            events.push(InputEvent::Text(text.to_string()));
+                        }
+                        events
+                    }
+                    ElementState::Released => {
+                        if let Some(code) = code {
+                            self.keys_down.remove(&code);
+                            self.keys_released.insert(code);
+                        }
+                        vec![InputEvent::KeyUp {
+                            key: event.logical_key.clone(),
+                            code,
+                        }]
+                    }
+                }
+            }
+            WindowEvent::Ime(Ime::Commit(text)) => {
+                self.text.push_str(text);
+                vec![InputEvent::Text(text.clone())]
+            }
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.modifiers = modifiers.state();
+                vec![InputEvent::ModifiersChanged(self.modifiers)]
+            }
+            WindowEvent::Touch(touch) => {
+                let position = to_canvas(touch.location, size);
+                match touch.phase {
+                    TouchPhase::Started | TouchPhase::Moved => {
+                        self.touches.insert(touch.id, position);
+                    }
+                    TouchPhase::Ended | TouchPhase::Cancelled => {
+                        self.touches.remove(&touch.id);
+                    }
+                }
+                vec![InputEvent::Touch {
+                    id: touch.id,
+                    phase: touch.phase,
+                    position,
+                }]
+            }
+            // Keys and buttons released while the window is unfocused are never reported.
+            WindowEvent::Focused(false) => {
+                self.buttons_down.clear();
+                self.keys_down.clear();
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Forgets the per frame state, called after every frame.
+    pub fn end_frame(&mut self) {
+        self.buttons_pressed.clear();
+        self.buttons_released.clear();
+        self.keys_pressed.clear();
+        self.keys_released.clear();
+        self.scroll = [0.0; 2];
+        self.text.clear();
+    }
+}
+
+fn to_canvas(position: PhysicalPosition<f64>, size: PhysicalSize<u32>) -> [f32; 2] {
+    [
+        (position.x / size.width.max(1) as f64 * 2.0 - 1.0) as f32,
+        (1.0 - position.y / size.height.max(1) as f64 * 2.0) as f32,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use winit::event::{DeviceId, Touch};
+
+    use super::*;
+
+    const SIZE: PhysicalSize<u32> = PhysicalSize::new(200, 100);
+
+    fn cursor_moved(x: f64, y: f64) -> WindowEvent {
+        WindowEvent::CursorMoved {
+            device_id: unsafe { DeviceId::dummy() },
+            position: PhysicalPosition::new(x, y),
+        }
+    }
+
+    #[test]
+    fn cursor_maps_to_canvas_coordinates() {
+        let mut input = Input::new();
+        for ([x, y], expected) in [
+            ([0.0, 0.0], [-1.0, 1.0]),
+            ([100.0, 50.0], [0.0, 0.0]),
+            ([200.0, 100.0], [1.0, -1.0]),
+            ([150.0, 25.0], [0.5, 0.5]),
+        ] {
+            let events = input.handle(&cursor_moved(x, y), SIZE, 1.0);
+            assert_eq!(events, vec![InputEvent::CursorMoved { position: expected }]);
+            assert_eq!(input.cursor(), Some(expected));
+        }
+    }
+
+    #[test]
+    fn logical_cursor_uses_scale_factor() {
+        let mut input = Input::new();
+        input.handle(&cursor_moved(150.0, 25.0), SIZE, 2.0);
+        assert_eq!(input.logical_cursor(), Some([75.0, 12.5]));
+        input.handle(
+            &WindowEvent::CursorLeft {
+                device_id: unsafe { DeviceId::dummy() },
+            },
+            SIZE,
+            2.0,
+        );
+        assert_eq!(input.cursor(), None);
+        assert_eq!(input.logical_cursor(), None);
+    }
+
+    #[test]
+    fn buttons_use_last_cursor_position() {
+        let mut input = Input::new();
+        input.handle(&cursor_moved(50.0, 75.0), SIZE, 1.0);
+        let events = input.handle(
+            &WindowEvent::MouseInput {
+                device_id: unsafe { DeviceId::dummy() },
+                state: ElementState::Pressed,
+                button: MouseButton::Left,
+            },
+            SIZE,
+            1.0,
+        );
+        assert_eq!(
+            events,
+            vec![InputEvent::MouseDown {
+                button: MouseButton::Left,
+                position: [-0.5, -0.5],
+            }]
+        );
+        assert!(input.is_button_down(MouseButton::Left));
+        assert!(input.was_button_pressed(MouseButton::Left));
+        input.end_frame();
+        assert!(input.is_button_down(MouseButton::Left));
+        assert!(!input.was_button_pressed(MouseButton::Left));
+    }
+
+    #[test]
+    fn touches_map_to_canvas_coordinates() {
+        let mut input = Input::new();
+        let touch = |phase| {
+            WindowEvent::Touch(Touch {
+                device_id: unsafe { DeviceId::dummy() },
+                phase,
+                location: PhysicalPosition::new(200.0, 0.0),
+                force: None,
+                id: 7,
+            })
+        };
+        input.handle(&touch(TouchPhase::Started), SIZE, 1.0);
+        assert_eq!(input.touches().get(&7), Some(&[1.0, 1.0]));
+        input.handle(&touch(TouchPhase::Ended), SIZE, 1.0);
+        assert!(input.touches().is_empty());
+    }
+
+    #[test]
+    fn ime_commit_produces_text() {
+        let mut input = Input::new();
+        let events = input.handle(&WindowEvent::Ime(Ime::Commit("日本".into())), SIZE, 1.0);
+        assert_eq!(events, vec![InputEvent::Text("日本".into())]);
+        assert_eq!(input.text(), "日本");
+        input.end_frame();
+        assert_eq!(input.text(), "");
+    }
+}
@@ -0,0 +1,654 @@
+use anyhow::Result;
+use lyon::math::{point, vector, Box2D};
+use wgpu::{Device, Queue, SurfaceConfiguration};
+
+use crate::blur::{self, BlurPasses, BlurPipeline};
+use crate::canvas::{Canvas, LayerItem, LayerItems, MaskMode, ScissorRect};
+use crate::material::MaterialPipeline;
+use crate::reload;
+use crate::tessellate::{TessellatePipeline, TessellateRange};
+use crate::texture::{Texture, TexturePipeline};
+use crate::view::View;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct LayerUniform {
+    opacity: f32,
+    mask_mode: u32,
+    shadow_offset: [f32; 2],
+    shadow_color: [f32; 4],
+    /// The screen rect of the layer in pixels: x, y, width and height.
+    rect: [f32; 4],
+    /// The screen position and texture size of the target the layer is composited into.
+    parent_origin: [f32; 2],
+    parent_size: [f32; 2],
+    /// The screen rect of the mask layer, whose texture is only sampled inside it.
+    mask_origin: [f32; 2],
+    mask_size: [f32; 2],
+}
+
+/// Targets are allocated in multiples of this many pixels, so layers whose bounds change a
+/// little can keep using theirs.
+const TARGET_GRANULARITY: u32 = 256;
+
+/// Targets that no layer was rendered into for this many frames are freed.
+const MAX_IDLE_FRAMES: u32 = 60;
+
+/// The intermediate color and depth-stencil textures a canvas layer is rendered into, as
+/// big as the bounds of the layer or bigger. Targets are pooled and reused by layers of
+/// later frames.
+pub struct LayerTarget {
+    /// Tells targets apart in the bind groups cached by others.
+    id: u64,
+    /// Single sampled, the resolve target when MSAA is enabled.
+    pub color: Texture,
+    pub depth: Texture,
+    /// The multisampled color texture when MSAA is enabled.
+    pub msaa: Option<Texture>,
+    /// The blurred copy of `color` for layers with a shadow.
+    pub shadow: Option<Texture>,
+    /// Holds the horizontal pass of blurs.
+    pub scratch: Option<Texture>,
+    pub shadow_passes: Option<BlurPasses>,
+    pub blur_passes: Option<BlurPasses>,
+    /// Places the layer on the screen.
+    pub view: View,
+    uniform_buffer: wgpu::Buffer,
+    /// The bind groups compositing the layer, with the key they were created for.
+    bind_groups: Option<(BindGroupKey, LayerBindGroups)>,
+    idle_frames: u32,
+}
+
+impl LayerTarget {
+    /// The size of the textures, of which `view.size` is used.
+    pub fn size(&self) -> (u32, u32) {
+        let size = self.color.texture.size();
+        (size.width, size.height)
+    }
+}
+
+/// What the bind groups of a target depend on besides its own textures: the id of the
+/// target of the mask layer and whether the layer has a shadow.
+type BindGroupKey = (Option<u64>, bool);
+
+/// The bind groups needed to composite one layer.
+pub struct LayerBindGroups {
+    pub layer: wgpu::BindGroup,
+    pub shadow: Option<wgpu::BindGroup>,
+}
+
+pub struct LayerPipeline {
+    pub render_pipeline: wgpu::RenderPipeline,
+    /// Draws the shadow of a layer from its `LayerTarget::shadow` texture.
+    pub shadow_pipeline: wgpu::RenderPipeline,
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    pub sample_count: u32,
+    targets: Vec<LayerTarget>,
+    /// The index into `targets` of each layer of the last prepared canvas, indexed like
+    /// `Canvas::layers`. Layers with nothing to draw have none.
+    assigned: Vec<Option<usize>>,
+    next_target_id: u64,
+}
+
+pub fn create_layer_pipeline(
+    device: &Device,
+    config: &SurfaceConfiguration,
+    sample_count: u32,
+) -> LayerPipeline {
+    let layer_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("Layer Shader"),
+        source: wgpu::ShaderSource::Wgsl(include_str!("shaders/layer_shader.wgsl").into()),
+    });
+
+    let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                // The vertex stage needs the size of the texture.
+                visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+        label: Some("layer_bind_group_layout"),
+    });
+
+    let [render_pipeline, shadow_pipeline] = create_pipelines(
+        device,
+        config,
+        sample_count,
+        &layer_shader,
+        &bind_group_layout,
+    );
+
+    LayerPipeline {
+        render_pipeline,
+        shadow_pipeline,
+        bind_group_layout,
+        sample_count,
+        targets: vec![],
+        assigned: vec![],
+        next_target_id: 0,
+    }
+}
+
+/// Creates the pipelines compositing layers and their shadows, which are all the pipelines
+/// using `shader`.
+fn create_pipelines(
+    device: &Device,
+    config: &SurfaceConfiguration,
+    sample_count: u32,
+    shader: &wgpu::ShaderModule,
+    bind_group_layout: &wgpu::BindGroupLayout,
+) -> [wgpu::RenderPipeline; 2] {
+    let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Layer Render Pipeline Layout"),
+        bind_group_layouts: &[bind_group_layout],
+        push_constant_ranges: &[],
+    });
+
+    let render_pipeline = create_composite_pipeline(
+        device,
+        &render_pipeline_layout,
+        shader,
+        config,
+        sample_count,
+        "Layer Render Pipeline",
+        "vs_main",
+        "fs_main",
+    );
+    let shadow_pipeline = create_composite_pipeline(
+        device,
+        &render_pipeline_layout,
+        shader,
+        config,
+        sample_count,
+        "Layer Shadow Pipeline",
+        "vs_shadow",
+        "fs_shadow",
+    );
+    [render_pipeline, shadow_pipeline]
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_composite_pipeline(
+    device: &Device,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    config: &SurfaceConfiguration,
+    sample_count: u32,
+    label: &str,
+    vertex_entry_point: &str,
+    fragment_entry_point: &str,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: vertex_entry_point,
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: fragment_entry_point,
+            targets: &[Some(wgpu::ColorTargetState {
+                format: config.format,
+                blend: Some(wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: None,
+            polygon_mode: wgpu::PolygonMode::Fill,
+            unclipped_depth: false,
+            conservative: false,
+        },
+        // Layers are composited on top of whatever was drawn before them.
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: Texture::DEPTH_FORMAT,
+            depth_write_enabled: false,
+            depth_compare: wgpu::CompareFunction::Always,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: sample_count,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+    })
+}
+
+impl LayerPipeline {
+    /// Recreates the pipelines with `shader`, a new build of `layer_shader.wgsl`. The
+    /// current pipelines are kept when that fails.
+    pub fn set_shader(
+        &mut self,
+        device: &Device,
+        config: &SurfaceConfiguration,
+        shader: &wgpu::ShaderModule,
+    ) -> Result<()> {
+        let pipelines = reload::capture_errors(device, || {
+            create_pipelines(
+                device,
+                config,
+                self.sample_count,
+                shader,
+                &self.bind_group_layout,
+            )
+        })?;
+        [self.render_pipeline, self.shadow_pipeline] = pipelines;
+        Ok(())
+    }
+
+    /// Assigns a target to each layer of `canvas` with something to draw, sized to the
+    /// screen rect the layer covers, and updates the uniforms and bind groups compositing
+    /// it. `items` are the items of `canvas`, see `Canvas::all_layer_items`.
+    pub fn prepare(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        config: &SurfaceConfiguration,
+        blur_pipeline: &BlurPipeline,
+        canvas: &Canvas,
+        items: &LayerItems,
+    ) {
+        let screen = (config.width, config.height);
+        let rects = layer_rects(canvas, items, screen);
+
+        for target in &mut self.targets {
+            target.idle_frames += 1;
+        }
+        self.targets
+            .retain(|target| target.idle_frames <= MAX_IDLE_FRAMES);
+        self.assigned = vec![None; canvas.layers.len()];
+        for (index, rect) in rects.iter().enumerate() {
+            let Some(rect) = rect else {
+                continue;
+            };
+            let layer = &canvas.layers[index];
+            let target_index = self.acquire(device, config, (rect.width, rect.height));
+            let target = &mut self.targets[target_index];
+            target.idle_frames = 0;
+            let sized_config = SurfaceConfiguration {
+                width: target.size().0,
+                height: target.size().1,
+                ..config.clone()
+            };
+            if layer.shadow.is_some() && target.shadow.is_none() {
+                target.shadow = Some(Texture::create_render_target(
+                    device,
+                    &sized_config,
+                    "layer_shadow_texture",
+                ));
+            }
+            if (layer.shadow.is_some() || layer.blur > 0.0) && target.scratch.is_none() {
+                let scratch =
+                    Texture::create_render_target(device, &sized_config, "layer_scratch_texture");
+                target.shadow_passes =
+                    Some(blur_pipeline.create_passes(device, &target.color, &scratch));
+                target.blur_passes =
+                    Some(blur_pipeline.create_passes(device, &target.color, &scratch));
+                target.scratch = Some(scratch);
+            }
+            let texture_size = target.size();
+            target.view.update(
+                queue,
+                screen,
+                [rect.x, rect.y],
+                (rect.width, rect.height),
+                texture_size,
+            );
+            self.assigned[index] = Some(target_index);
+        }
+
+        // The uniforms and bind groups refer to the targets of parents and masks, so they
+        // are updated once every layer has one.
+        for (index, layer) in canvas.layers.iter().enumerate() {
+            let Some(target_index) = self.assigned[index] else {
+                continue;
+            };
+            let (parent_origin, parent_size) = match layer.parent.and_then(|p| self.assigned[p]) {
+                Some(parent) => (
+                    self.targets[parent].view.origin,
+                    self.targets[parent].size(),
+                ),
+                None => ([0; 2], screen),
+            };
+            let (mask_mode, mask_target) = match layer.mask {
+                None => (0, None),
+                Some(mask) => {
+                    let mode = match mask.mode {
+                        MaskMode::Alpha => 1,
+                        MaskMode::Luminance => 2,
+                    };
+                    (mode, self.assigned[mask.layer])
+                }
+            };
+            // A mask without a target has nothing drawn, which hides the whole layer.
+            let (mask_origin, mask_size) = mask_target.map_or(([0; 2], (0, 0)), |mask| {
+                let view = &self.targets[mask].view;
+                (view.origin, view.size)
+            });
+            let target = &self.targets[target_index];
+            let uniform = LayerUniform {
+                opacity: layer.opacity,
+                mask_mode,
+                shadow_offset: layer.shadow.map_or([0.0; 2], |shadow| shadow.offset),
+                shadow_color: layer.shadow.map_or([0.0; 4], |shadow| shadow.color),
+                rect: [
+                    target.view.origin[0] as f32,
+                    target.view.origin[1] as f32,
+                    target.view.size.0 as f32,
+                    target.view.size.1 as f32,
+                ],
+                parent_origin: parent_origin.map(|x| x as f32),
+                parent_size: [parent_size.0 as f32, parent_size.1 as f32],
+                mask_origin: mask_origin.map(|x| x as f32),
+                mask_size: [mask_size.0 as f32, mask_size.1 as f32],
+            };
+            queue.write_buffer(&target.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+
+            let key = (
+                mask_target.map(|mask| self.targets[mask].id),
+                layer.shadow.is_some(),
+            );
+            if target.bind_groups.as_ref().map(|(cached, _)| *cached) == Some(key) {
+                continue;
+            }
+            let mask = mask_target.map_or(&target.color, |mask| &self.targets[mask].color);
+            let bind_groups = LayerBindGroups {
+                layer: self.create_bind_group(device, &target.color, mask, &target.uniform_buffer),
+                shadow: target
+                    .shadow
+                    .as_ref()
+                    .filter(|_| layer.shadow.is_some())
+                    .map(|texture| {
+                        self.create_bind_group(device, texture, mask, &target.uniform_buffer)
+                    }),
+            };
+            self.targets[target_index].bind_groups = Some((key, bind_groups));
+        }
+    }
+
+    /// The target `layer` was assigned by the last `prepare`, if it has anything to draw.
+    pub fn target(&self, layer: usize) -> Option<&LayerTarget> {
+        self.assigned
+            .get(layer)
+            .copied()
+            .flatten()
+            .map(|index| &self.targets[index])
+    }
+
+    /// Returns the index of the smallest target not used this frame that is at least `size`
+    /// big, creating one if there is none.
+    fn acquire(
+        &mut self,
+        device: &Device,
+        config: &SurfaceConfiguration,
+        size: (u32, u32),
+    ) -> usize {
+        let free = self
+            .targets
+            .iter()
+            .enumerate()
+            .filter(|(_, target)| {
+                let (width, height) = target.size();
+                target.idle_frames > 0 && width >= size.0 && height >= size.1
+            })
+            .min_by_key(|(_, target)| {
+                let (width, height) = target.size();
+                width as u64 * height as u64
+            });
+        if let Some((index, _)) = free {
+            return index;
+        }
+
+        // Rounded up so the target fits the layer for a while if it grows, but never
+        // bigger than the screen.
+        let round =
+            |size: u32, max: u32| size.next_multiple_of(TARGET_GRANULARITY).min(max).max(size);
+        let config = SurfaceConfiguration {
+            width: round(size.0, config.width),
+            height: round(size.1, config.height),
+            ..config.clone()
+        };
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Layer Uniform Buffer"),
+            size: std::mem::size_of::<LayerUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        self.targets.push(LayerTarget {
+            id: self.next_target_id,
+            color: Texture::create_render_target(device, &config, "layer_texture"),
+            depth: Texture::create_depth_texture(
+                device,
+                &config,
+                self.sample_count,
+                "layer_depth_texture",
+            ),
+            msaa: (self.sample_count > 1).then(|| {
+                Texture::create_multisampled_target(
+                    device,
+                    &config,
+                    self.sample_count,
+                    "layer_msaa_texture",
+                )
+            }),
+            shadow: None,
+            scratch: None,
+            shadow_passes: None,
+            blur_passes: None,
+            view: View::new(device, (config.width, config.height)),
+            uniform_buffer,
+            bind_groups: None,
+            idle_frames: 0,
+        });
+        self.next_target_id += 1;
+        self.targets.len() - 1
+    }
+
+    /// Frees every target, whose size depends on the screen.
+    pub fn resize(&mut self) {
+        self.targets.clear();
+        self.assigned.clear();
+    }
+
+    fn create_bind_group(
+        &self,
+        device: &Device,
+        texture: &Texture,
+        mask: &Texture,
+        uniform_buffer: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&mask.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("layer_bind_group"),
+        })
+    }
+
+    /// Draws the shapes in `items` through `view` and composites its child layers, with
+    /// their shadows, in between, using the targets and bind groups from `prepare`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_items<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        tessellate_pipeline: &'a TessellatePipeline,
+        texture_pipeline: &'a TexturePipeline,
+        material_pipeline: &'a MaterialPipeline,
+        canvas: &Canvas,
+        ranges: &[TessellateRange],
+        items: &[LayerItem],
+        view: &'a View,
+    ) {
+        let mut shapes = vec![];
+        for item in items {
+            match *item {
+                LayerItem::Shape(shape) => shapes.push(shape),
+                LayerItem::Layer(layer) if !canvas.layers[layer].is_mask => {
+                    let Some((_, bind_groups)) = self
+                        .target(layer)
+                        .and_then(|target| target.bind_groups.as_ref())
+                    else {
+                        continue;
+                    };
+                    tessellate_pipeline.draw(
+                        render_pass,
+                        texture_pipeline,
+                        material_pipeline,
+                        canvas,
+                        ranges,
+                        &shapes,
+                        view,
+                    );
+                    shapes.clear();
+                    if let Some(shadow) = &bind_groups.shadow {
+                        render_pass.set_pipeline(&self.shadow_pipeline);
+                        render_pass.set_bind_group(0, shadow, &[]);
+                        render_pass.draw(0..6, 0..1);
+                    }
+                    render_pass.set_pipeline(&self.render_pipeline);
+                    render_pass.set_bind_group(0, &bind_groups.layer, &[]);
+                    render_pass.draw(0..6, 0..1);
+                }
+                LayerItem::Layer(_) => {}
+            }
+        }
+        tessellate_pipeline.draw(
+            render_pass,
+            texture_pipeline,
+            material_pipeline,
+            canvas,
+            ranges,
+            &shapes,
+            view,
+        );
+    }
+}
+
+/// The screen rect in pixels each layer of `canvas` is rendered into: the bounds of its
+/// shapes and composited child layers, grown by how far its blurs reach and clipped to the
+/// screen. Layers with nothing to draw have none.
+fn layer_rects(
+    canvas: &Canvas,
+    items: &LayerItems,
+    screen: (u32, u32),
+) -> Vec<Option<ScissorRect>> {
+    let (width, height) = (screen.0 as f32, screen.1 as f32);
+    let screen_box = Box2D::new(point(0.0, 0.0), point(width, height));
+    let mut rects = vec![None; canvas.layers.len()];
+    // The screen area each layer covers once composited, shadow included.
+    let mut extents: Vec<Option<Box2D>> = vec![None; canvas.layers.len()];
+    // Child layers come after their parents, so they are done first backwards.
+    for index in (0..canvas.layers.len()).rev() {
+        let mut content: Option<Box2D> = None;
+        for item in &items.layers[index] {
+            let bounds = match *item {
+                LayerItem::Shape(shape) => {
+                    let tessellate = &canvas.tessellates[shape];
+                    if tessellate.vertices.is_empty() {
+                        continue;
+                    }
+                    // Canvas coordinates have y up, pixels y down.
+                    let bounds = tessellate.bounds;
+                    Box2D::new(
+                        point(
+                            (bounds.min.x + 1.0) * 0.5 * width,
+                            (1.0 - bounds.max.y) * 0.5 * height,
+                        ),
+                        point(
+                            (bounds.max.x + 1.0) * 0.5 * width,
+                            (1.0 - bounds.min.y) * 0.5 * height,
+                        ),
+                    )
+                }
+                LayerItem::Layer(child) if !canvas.layers[child].is_mask => match extents[child] {
+                    Some(extent) => extent,
+                    None => continue,
+                },
+                LayerItem::Layer(_) => continue,
+            };
+            content = Some(content.map_or(bounds, |content| content.union(&bounds)));
+        }
+        let Some(content) = content else {
+            continue;
+        };
+
+        let layer = &canvas.layers[index];
+        let shadow_radius = layer.shadow.map_or(0, |shadow| blur::radius(shadow.blur));
+        let margin = blur::radius(layer.blur).max(shadow_radius) as f32;
+        let Some(rect) = content
+            .inflate(margin, margin)
+            .round_out()
+            .intersection(&screen_box)
+            .filter(|rect| !rect.is_empty())
+        else {
+            continue;
+        };
+        rects[index] = Some(ScissorRect::new(
+            rect.min.x as u32,
+            rect.min.y as u32,
+            rect.width() as u32,
+            rect.height() as u32,
+        ));
+        extents[index] = Some(match layer.shadow {
+            Some(shadow) => rect.union(&rect.translate(vector(shadow.offset[0], shadow.offset[1]))),
+            None => rect,
+        });
+    }
+    rects
+}
@@ -0,0 +1,41 @@
+mod app;
+mod atlas;
+mod blur;
+mod cache;
+pub mod canvas;
+mod colormap;
+mod input;
+mod layer;
+mod material;
+mod reload;
+mod renderer;
+mod sprite;
+mod store;
+pub mod tessellate;
+pub mod texture;
+mod view;
+mod wgpu_winit;
+
+pub use app::{App, Context};
+pub use atlas::{AtlasImage, RectPacker, TextureAtlas};
+pub use blur::MAX_BLUR_SIGMA;
+pub use cache::{Geometry, TessellateOptions, TessellationCache};
+pub use canvas::{
+    Canvas, Insets, Line, Mask, MaskMode, PathStyle, ScissorRect, Shadow, ShapeId, TextureFill,
+};
+pub use colormap::{Colormap, Heatmap};
+pub use input::{Input, InputEvent};
+pub use material::{MaterialId, MaterialPipeline};
+pub use reload::{HotReload, ReloadEvent, Shader};
+pub use renderer::{supported_sample_count, Renderer, RendererDescriptor};
+pub use sprite::{
+    AnimatedSprite, Animation, Direction, Sprite, SpriteBatch, SpriteFrame, SpriteSheet,
+};
+pub use store::{TextureHandle, TextureId, TextureStore};
+pub use wgpu_winit::{run, Config};
+
+pub use lyon::math::Transform;
+
+// The winit types that appear in `InputEvent` and `Input`.
+pub use winit::event::{MouseButton, TouchPhase};
+pub use winit::keyboard::{Key, KeyCode, ModifiersState};
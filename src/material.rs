@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use wgpu::{Device, Queue, SurfaceConfiguration};
+
+use crate::reload;
+use crate::tessellate::{clip_stencil_state, TessellateVertex};
+use crate::texture::Texture;
+use crate::view::{self, View};
+
+/// The interface between the renderer and materials, see `MaterialPipeline::register`.
+const PRELUDE: &str = include_str!("shaders/material_prelude.wgsl");
+
+/// The size of the uniform buffer of a material before `MaterialPipeline::set_uniforms`
+/// grows it.
+const MIN_UNIFORM_SIZE: u64 = 256;
+
+/// A material registered with `MaterialPipeline::register`, see `Canvas::set_material`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MaterialId(usize);
+
+struct Material {
+    /// Index into `MaterialPipeline::pipelines`.
+    pipeline: usize,
+    uniform_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+/// Draws shapes with custom WGSL fragment functions instead of their color.
+pub struct MaterialPipeline {
+    bind_group_layout: wgpu::BindGroupLayout,
+    pipeline_layout: wgpu::PipelineLayout,
+    format: wgpu::TextureFormat,
+    sample_count: u32,
+    pipelines: Vec<wgpu::RenderPipeline>,
+    /// The index into `pipelines` of every source compiled so far, so materials with the
+    /// same source share one pipeline.
+    sources: HashMap<String, usize>,
+    materials: Vec<Material>,
+}
+
+pub fn create_material_pipeline(
+    device: &Device,
+    config: &SurfaceConfiguration,
+    sample_count: u32,
+) -> MaterialPipeline {
+    let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("material_bind_group_layout"),
+        entries: &[wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        }],
+    });
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Material Render Pipeline Layout"),
+        bind_group_layouts: &[&bind_group_layout, &view::create_bind_group_layout(device)],
+        push_constant_ranges: &[],
+    });
+
+    MaterialPipeline {
+        bind_group_layout,
+        pipeline_layout,
+        format: config.format,
+        sample_count,
+        pipelines: vec![],
+        sources: HashMap::new(),
+        materials: vec![],
+    }
+}
+
+impl MaterialPipeline {
+    /// Compiles `source` into a material that shapes can be drawn with, see
+    /// `Canvas::set_material`. The source has to define
+    ///
+    /// ```wgsl
+    /// fn material(in: MaterialInput) -> vec4<f32>
+    /// ```
+    ///
+    /// which returns the color of a fragment, blended like the colors of other shapes. The
+    /// fields of `MaterialInput` are
+    ///
+    /// - `frag_position: vec4<f32>`, the framebuffer position in pixels,
+    /// - `color: vec4<f32>`, the color of the shape,
+    /// - `position: vec2<f32>`, the canvas position,
+    /// - `uv: vec2<f32>`, the position inside the bounds of the path, from (0, 0) at the
+    ///   top left to (1, 1) at the bottom right.
+    ///
+    /// The uniforms set with `set_uniforms` are declared as
+    /// `@group(0) @binding(0) var<uniform> uniforms: T;`. Every material has uniforms of
+    /// its own, even when the source is shared with others, whose pipeline is reused.
+    ///
+    /// Errors, like WGSL that does not compile, are returned. Their line numbers count
+    /// the lines of `shaders/material_prelude.wgsl` that are put in front of `source`.
+    pub fn register(&mut self, device: &Device, source: &str) -> Result<MaterialId> {
+        let pipeline = self.compile(device, source)?;
+        let uniform_buffer = create_uniform_buffer(device, MIN_UNIFORM_SIZE);
+        let bind_group = self.create_bind_group(device, &uniform_buffer);
+        self.materials.push(Material {
+            pipeline,
+            uniform_buffer,
+            bind_group,
+        });
+        Ok(MaterialId(self.materials.len() - 1))
+    }
+
+    /// Replaces the source of the material `id`, keeping its uniforms. On errors the
+    /// previous source stays in use, which suits reloading it while the app runs, see
+    /// `App::file_changed`.
+    pub fn set_source(&mut self, device: &Device, id: MaterialId, source: &str) -> Result<()> {
+        if id.0 >= self.materials.len() {
+            return Err(anyhow!("unknown material {id:?}"));
+        }
+        self.materials[id.0].pipeline = self.compile(device, source)?;
+        Ok(())
+    }
+
+    /// Replaces the uniforms of the material `id` with `uniforms`, which has to match the
+    /// layout WGSL gives the uniform struct of the material, padding included.
+    pub fn set_uniforms<T: bytemuck::Pod>(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        id: MaterialId,
+        uniforms: &T,
+    ) -> Result<()> {
+        if id.0 >= self.materials.len() {
+            return Err(anyhow!("unknown material {id:?}"));
+        }
+        // Buffer writes have to be a multiple of 4 bytes long, uniform structs are one of
+        // 16 anyway.
+        let mut data = bytemuck::bytes_of(uniforms).to_vec();
+        data.resize(data.len().next_multiple_of(16), 0);
+        if data.len() as u64 > self.materials[id.0].uniform_buffer.size() {
+            let uniform_buffer = create_uniform_buffer(device, data.len() as u64);
+            let bind_group = self.create_bind_group(device, &uniform_buffer);
+            let material = &mut self.materials[id.0];
+            material.uniform_buffer = uniform_buffer;
+            material.bind_group = bind_group;
+        }
+        queue.write_buffer(&self.materials[id.0].uniform_buffer, 0, &data);
+        Ok(())
+    }
+
+    /// Sets the pipeline and uniforms of the material `id` and `view`, and returns false for
+    /// unknown ids, which leaves the render pass untouched.
+    pub fn bind<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        id: MaterialId,
+        view: &'a View,
+    ) -> bool {
+        let Some(material) = self.materials.get(id.0) else {
+            return false;
+        };
+        render_pass.set_pipeline(&self.pipelines[material.pipeline]);
+        render_pass.set_bind_group(0, &material.bind_group, &[]);
+        render_pass.set_bind_group(1, &view.bind_group, &[]);
+        true
+    }
+
+    /// Returns the index of the pipeline for `source`, which is only compiled once.
+    fn compile(&mut self, device: &Device, source: &str) -> Result<usize> {
+        if let Some(&pipeline) = self.sources.get(source) {
+            return Ok(pipeline);
+        }
+        let pipeline = reload::capture_errors(device, || {
+            let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some("Material Shader"),
+                source: wgpu::ShaderSource::Wgsl(format!("{PRELUDE}\n{source}").into()),
+            });
+            create_render_pipeline(
+                device,
+                &self.pipeline_layout,
+                &shader,
+                self.format,
+                self.sample_count,
+            )
+        })?;
+        self.pipelines.push(pipeline);
+        self.sources
+            .insert(source.to_string(), self.pipelines.len() - 1);
+        Ok(self.pipelines.len() - 1)
+    }
+
+    fn create_bind_group(&self, device: &Device, uniform_buffer: &wgpu::Buffer) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("material_bind_group"),
+            layout: &self.bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            }],
+        })
+    }
+}
+
+/// A zeroed uniform buffer of `size` bytes.
+fn create_uniform_buffer(device: &Device, size: u64) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Material Uniform Buffer"),
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+        size,
+    })
+}
+
+/// Creates a pipeline like the render pipeline of the `TessellatePipeline`, with the
+/// fragment function of a material.
+fn create_render_pipeline(
+    device: &Device,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    format: wgpu::TextureFormat,
+    sample_count: u32,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Material Render Pipeline"),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_main",
+            buffers: &[TessellateVertex::desc()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState {
+                    color: wgpu::BlendComponent {
+                        src_factor: wgpu::BlendFactor::SrcAlpha,
+                        dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                        operation: wgpu::BlendOperation::Add,
+                    },
+                    alpha: wgpu::BlendComponent::OVER,
+                }),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            // Transforms may mirror the shapes.
+            cull_mode: None,
+            polygon_mode: wgpu::PolygonMode::Fill,
+            unclipped_depth: false,
+            conservative: false,
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: Texture::DEPTH_FORMAT,
+            depth_write_enabled: false,
+            depth_compare: wgpu::CompareFunction::Always,
+            stencil: clip_stencil_state(wgpu::StencilOperation::Keep, 0x00),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: sample_count,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+    })
+}
@@ -0,0 +1,213 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+use anyhow::{anyhow, Result};
+use notify::{EventKind, RecursiveMode, Watcher};
+use wgpu::{Device, Queue};
+
+use crate::renderer::Renderer;
+
+/// Runs `create` and returns the validation errors it caused, like WGSL compile errors,
+/// instead of handing them to the uncaptured error handler, which panics.
+pub(crate) fn capture_errors<T>(device: &Device, create: impl FnOnce() -> T) -> Result<T> {
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let value = create();
+    match pollster::block_on(device.pop_error_scope()) {
+        Some(error) => Err(anyhow!("{error}")),
+        None => Ok(value),
+    }
+}
+
+/// The shaders of the renderer that can be replaced while it runs, see
+/// `Renderer::reload_shader`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Shader {
+    Tessellate,
+    Texture,
+    Layer,
+    Blur,
+}
+
+impl Shader {
+    pub const ALL: [Shader; 4] = [
+        Shader::Tessellate,
+        Shader::Texture,
+        Shader::Layer,
+        Shader::Blur,
+    ];
+
+    /// The name of the file in `src/shaders` the shader is built into the crate from.
+    pub fn file_name(self) -> &'static str {
+        match self {
+            Shader::Tessellate => "tessellate_shader.wgsl",
+            Shader::Texture => "texture_shader.wgsl",
+            Shader::Layer => "layer_shader.wgsl",
+            Shader::Blur => "blur_shader.wgsl",
+        }
+    }
+
+    pub fn from_file_name(name: &str) -> Option<Shader> {
+        Shader::ALL
+            .into_iter()
+            .find(|shader| shader.file_name() == name)
+    }
+}
+
+/// What `HotReload::poll` did about a changed file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReloadEvent {
+    /// The shader was compiled again and the pipelines using it were recreated.
+    Shader(Shader),
+    /// The textures loaded from the file were uploaded again.
+    Texture(PathBuf),
+    /// A file added with `HotReload::watch` changed, which is left to the app.
+    Changed(PathBuf),
+    /// The file could not be reloaded, the renderer keeps using the previous version.
+    Failed { path: PathBuf, error: String },
+}
+
+/// Watches the shaders of the renderer and the image files of its textures during
+/// development, and reloads them when they change on disk. Shaders with errors are
+/// reported and the renderer keeps drawing with the last version that compiled.
+pub struct HotReload {
+    watcher: notify::RecommendedWatcher,
+    events: mpsc::Receiver<notify::Result<notify::Event>>,
+    shader_dir: PathBuf,
+    /// The directories watched so far. Directories rather than files are watched, since
+    /// editors often save by replacing the file.
+    directories: HashSet<PathBuf>,
+    /// The files added with `watch`.
+    files: HashSet<PathBuf>,
+    /// The canonical paths of the texture files, by the path they were loaded with.
+    textures: HashMap<PathBuf, PathBuf>,
+}
+
+impl HotReload {
+    /// Watches the shaders in `shader_dir`, see `source_shader_dir`.
+    pub fn new(shader_dir: impl AsRef<Path>) -> Result<HotReload> {
+        let (sender, events) = mpsc::channel();
+        let watcher = notify::recommended_watcher(move |event| {
+            // Only fails once the `HotReload` is dropped.
+            let _ = sender.send(event);
+        })?;
+        let shader_dir = shader_dir.as_ref().canonicalize()?;
+        let mut reload = HotReload {
+            watcher,
+            events,
+            shader_dir: shader_dir.clone(),
+            directories: HashSet::new(),
+            files: HashSet::new(),
+            textures: HashMap::new(),
+        };
+        reload.watch_directory(&shader_dir)?;
+        Ok(reload)
+    }
+
+    /// The shader directory of the source tree the crate was built from.
+    pub fn source_shader_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("src/shaders")
+    }
+
+    /// Also watches `path`, changes to which `poll` reports as `ReloadEvent::Changed`.
+    pub fn watch(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref().canonicalize()?;
+        if let Some(directory) = path.parent() {
+            self.watch_directory(directory)?;
+        }
+        self.files.insert(path);
+        Ok(())
+    }
+
+    /// Reloads the shaders and textures that changed since the last call. Call it once
+    /// per frame, before drawing.
+    pub fn poll(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        renderer: &mut Renderer,
+    ) -> Vec<ReloadEvent> {
+        // Textures may have been loaded or freed since the last call. Only new paths are
+        // resolved, which touches the file system.
+        let loaded: HashSet<&Path> = renderer.textures().paths().collect();
+        self.textures
+            .retain(|path, _| loaded.contains(path.as_path()));
+        for path in loaded {
+            if self.textures.contains_key(path) {
+                continue;
+            }
+            let Ok(canonical) = path.canonicalize() else {
+                continue;
+            };
+            if let Some(directory) = canonical.parent() {
+                if let Err(error) = self.watch_directory(directory) {
+                    log::warn!("cannot watch {}: {error:#}", directory.display());
+                }
+            }
+            self.textures.insert(path.to_path_buf(), canonical);
+        }
+
+        // Editors often write a file in several steps, each reported, so every file is
+        // only reloaded once.
+        let mut changed: Vec<PathBuf> = vec![];
+        for event in self.events.try_iter() {
+            match event {
+                Ok(event) if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) => {
+                    for path in event.paths {
+                        if !changed.contains(&path) {
+                            changed.push(path);
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(error) => log::warn!("file watcher: {error}"),
+            }
+        }
+
+        let mut events = vec![];
+        for path in changed {
+            let shader = path
+                .parent()
+                .filter(|directory| *directory == self.shader_dir)
+                .and_then(|_| path.file_name()?.to_str())
+                .and_then(Shader::from_file_name);
+            let texture = self
+                .textures
+                .iter()
+                .find(|(_, canonical)| **canonical == path)
+                .map(|(loaded, _)| loaded.clone());
+            let result = if let Some(shader) = shader {
+                std::fs::read_to_string(&path)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|source| renderer.reload_shader(device, shader, &source))
+                    .map(|_| ReloadEvent::Shader(shader))
+            } else if let Some(loaded) = texture {
+                renderer
+                    .textures_mut()
+                    .reload_path(device, queue, loaded)
+                    .map(|_| ReloadEvent::Texture(path.clone()))
+            } else if self.files.contains(&path) {
+                Ok(ReloadEvent::Changed(path.clone()))
+            } else {
+                continue;
+            };
+            let event = result.unwrap_or_else(|error| {
+                log::error!("cannot reload {}: {error:#}", path.display());
+                ReloadEvent::Failed {
+                    path,
+                    error: format!("{error:#}"),
+                }
+            });
+            events.push(event);
+        }
+        events
+    }
+
+    fn watch_directory(&mut self, directory: &Path) -> Result<()> {
+        if !self.directories.contains(directory) {
+            self.watcher.watch(directory, RecursiveMode::NonRecursive)?;
+            self.directories.insert(directory.to_path_buf());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,392 @@
+use std::iter;
+
+use anyhow::Result;
+use lyon::math::{point, Box2D};
+use wgpu::{Device, Queue};
+
+use crate::blur::{self, BlurPipeline};
+use crate::canvas::{Canvas, LayerItem, LayerItems};
+use crate::layer::{self, LayerPipeline};
+use crate::material::{self, MaterialPipeline};
+use crate::reload::{self, Shader};
+use crate::store::TextureStore;
+use crate::tessellate::{self, TessellatePipeline, TessellateRange};
+use crate::texture::{self, Texture, TexturePipeline};
+use crate::view::View;
+
+/// Describes the target a `Renderer` draws into.
+#[derive(Debug, Clone)]
+pub struct RendererDescriptor {
+    /// The format of the texture views passed to `Renderer::render`.
+    pub format: wgpu::TextureFormat,
+    pub width: u32,
+    pub height: u32,
+    /// The MSAA sample count. Use `supported_sample_count` to pick one the adapter supports.
+    pub sample_count: u32,
+}
+
+/// Draws a `Canvas` into a texture view with a device and queue owned by the caller.
+pub struct Renderer {
+    // Only the format and size are used, the renderer never touches a surface.
+    config: wgpu::SurfaceConfiguration,
+    sample_count: u32,
+    /// The multisampled color target, `None` when the sample count is 1.
+    msaa_texture: Option<Texture>,
+    depth_texture: Texture,
+    /// Draws canvas coordinates unchanged onto the whole screen.
+    view: View,
+    tessellate_pipeline: TessellatePipeline,
+    texture_pipeline: TexturePipeline,
+    material_pipeline: MaterialPipeline,
+    layer_pipeline: LayerPipeline,
+    blur_pipeline: BlurPipeline,
+}
+
+impl Renderer {
+    pub fn new(device: &Device, descriptor: &RendererDescriptor) -> Renderer {
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            format: descriptor.format,
+            width: descriptor.width.max(1),
+            height: descriptor.height.max(1),
+            present_mode: wgpu::PresentMode::Fifo,
+            alpha_mode: wgpu::CompositeAlphaMode::Auto,
+            view_formats: vec![],
+            desired_maximum_frame_latency: 1,
+        };
+        let sample_count = descriptor.sample_count;
+
+        Renderer {
+            msaa_texture: create_msaa_texture(device, &config, sample_count),
+            depth_texture: Texture::create_depth_texture(
+                device,
+                &config,
+                sample_count,
+                "depth_texture",
+            ),
+            view: View::new(device, (config.width, config.height)),
+            tessellate_pipeline: tessellate::create_tessellate_pipeline(
+                device,
+                &config,
+                sample_count,
+            ),
+            texture_pipeline: texture::create_texture_pipeline(device, &config, sample_count),
+            material_pipeline: material::create_material_pipeline(device, &config, sample_count),
+            layer_pipeline: layer::create_layer_pipeline(device, &config, sample_count),
+            blur_pipeline: blur::create_blur_pipeline(device, &config),
+            config,
+            sample_count,
+        }
+    }
+
+    pub fn sample_count(&self) -> u32 {
+        self.sample_count
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.config.width, self.config.height)
+    }
+
+    /// The textures that can be drawn with `Canvas::draw_texture`.
+    pub fn textures(&self) -> &TextureStore {
+        &self.texture_pipeline.textures
+    }
+
+    pub fn textures_mut(&mut self) -> &mut TextureStore {
+        &mut self.texture_pipeline.textures
+    }
+
+    /// The materials that can be drawn with `Canvas::set_material`.
+    pub fn materials(&self) -> &MaterialPipeline {
+        &self.material_pipeline
+    }
+
+    pub fn materials_mut(&mut self) -> &mut MaterialPipeline {
+        &mut self.material_pipeline
+    }
+
+    /// The textures and materials at once, for the `Context` of apps.
+    pub(crate) fn resources_mut(&mut self) -> (&mut TextureStore, &mut MaterialPipeline) {
+        (
+            &mut self.texture_pipeline.textures,
+            &mut self.material_pipeline,
+        )
+    }
+
+    /// Compiles `source` as a new version of `shader` and recreates the pipelines using it.
+    /// On errors, like WGSL that does not compile, the previous pipelines stay in use.
+    pub fn reload_shader(&mut self, device: &Device, shader: Shader, source: &str) -> Result<()> {
+        let module = reload::capture_errors(device, || {
+            device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some(shader.file_name()),
+                source: wgpu::ShaderSource::Wgsl(source.into()),
+            })
+        })?;
+        let config = &self.config;
+        match shader {
+            Shader::Tessellate => {
+                self.tessellate_pipeline
+                    .set_shader(device, config, self.sample_count, &module)
+            }
+            Shader::Texture => {
+                self.texture_pipeline
+                    .set_shader(device, config, self.sample_count, &module)
+            }
+            Shader::Layer => self.layer_pipeline.set_shader(device, config, &module),
+            Shader::Blur => self.blur_pipeline.set_shader(device, config, &module),
+        }
+    }
+
+    /// Recreates the size dependent textures. Zero sizes are ignored.
+    pub fn resize(&mut self, device: &Device, width: u32, height: u32) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        self.config.width = width;
+        self.config.height = height;
+        self.depth_texture =
+            Texture::create_depth_texture(device, &self.config, self.sample_count, "depth_texture");
+        self.msaa_texture = create_msaa_texture(device, &self.config, self.sample_count);
+        self.view.size = (width, height);
+        self.layer_pipeline.resize();
+    }
+
+    /// Clears `view` with `clear` and draws `canvas` on top of it, after tessellating its
+    /// deferred shapes with `Canvas::flush`. `view` has to have the format and size the
+    /// renderer was created or last resized with.
+    pub fn render(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        canvas: &mut Canvas,
+        view: &wgpu::TextureView,
+        clear: wgpu::Color,
+    ) {
+        canvas.flush();
+        self.texture_pipeline.textures.free_unused();
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Render Encoder"),
+        });
+
+        // Off-screen shapes are not uploaded, they get an empty range so `draw` skips them.
+        let viewport = Box2D::new(point(-1.0, -1.0), point(1.0, 1.0));
+        let visible = canvas.visible_shapes(viewport, self.size());
+        let mut ranges = self
+            .tessellate_pipeline
+            .upload(device, &mut encoder, canvas, &visible);
+        self.texture_pipeline
+            .upload(device, queue, canvas, &visible, &mut ranges);
+        let items = canvas.all_layer_items();
+        self.layer_pipeline.prepare(
+            device,
+            queue,
+            &self.config,
+            &self.blur_pipeline,
+            canvas,
+            &items,
+        );
+
+        let mut rendered = vec![false; canvas.layers.len()];
+        self.render_child_layers(
+            queue,
+            &mut encoder,
+            canvas,
+            &ranges,
+            &items,
+            &items.root,
+            &mut rendered,
+        );
+
+        {
+            let mut render_pass = begin_render_pass(
+                &mut encoder,
+                view,
+                self.msaa_texture.as_ref(),
+                &self.depth_texture.view,
+                clear,
+            );
+            self.layer_pipeline.draw_items(
+                &mut render_pass,
+                &self.tessellate_pipeline,
+                &self.texture_pipeline,
+                &self.material_pipeline,
+                canvas,
+                &ranges,
+                &items.root,
+                &self.view,
+            );
+        }
+
+        queue.submit(iter::once(encoder.finish()));
+        self.tessellate_pipeline.staging_belt.recall();
+    }
+
+    /// Renders every layer in `children`, and the layers used as their masks, into their
+    /// targets.
+    #[allow(clippy::too_many_arguments)]
+    fn render_child_layers(
+        &self,
+        queue: &Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        canvas: &Canvas,
+        ranges: &[TessellateRange],
+        items: &LayerItems,
+        children: &[LayerItem],
+        rendered: &mut [bool],
+    ) {
+        for item in children {
+            if let LayerItem::Layer(layer) = *item {
+                self.render_layer(queue, encoder, canvas, ranges, items, layer, rendered);
+                if let Some(mask) = canvas.layers[layer].mask {
+                    self.render_layer(queue, encoder, canvas, ranges, items, mask.layer, rendered);
+                }
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn render_layer(
+        &self,
+        queue: &Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        canvas: &Canvas,
+        ranges: &[TessellateRange],
+        items: &LayerItems,
+        layer: usize,
+        rendered: &mut [bool],
+    ) {
+        if rendered[layer] {
+            return;
+        }
+        rendered[layer] = true;
+
+        // Child layers have to be finished before they are composited into this one.
+        let children = &items.layers[layer];
+        self.render_child_layers(queue, encoder, canvas, ranges, items, children, rendered);
+        let Some(target) = self.layer_pipeline.target(layer) else {
+            return;
+        };
+        {
+            let mut render_pass = begin_render_pass(
+                encoder,
+                &target.color.view,
+                target.msaa.as_ref(),
+                &target.depth.view,
+                wgpu::Color::TRANSPARENT,
+            );
+            self.layer_pipeline.draw_items(
+                &mut render_pass,
+                &self.tessellate_pipeline,
+                &self.texture_pipeline,
+                &self.material_pipeline,
+                canvas,
+                ranges,
+                children,
+                &target.view,
+            );
+        }
+
+        // The shadow is taken from the sharp layer, before the layer itself is blurred.
+        let layer = &canvas.layers[layer];
+        let size = target.view.size;
+        if let (Some(shadow), Some(shadow_texture), Some(scratch), Some(passes)) = (
+            layer.shadow,
+            &target.shadow,
+            &target.scratch,
+            &target.shadow_passes,
+        ) {
+            self.blur_pipeline.blur(
+                queue,
+                encoder,
+                passes,
+                scratch,
+                shadow_texture,
+                size,
+                shadow.blur,
+            );
+        }
+        if let (true, Some(scratch), Some(passes)) =
+            (layer.blur > 0.0, &target.scratch, &target.blur_passes)
+        {
+            self.blur_pipeline.blur(
+                queue,
+                encoder,
+                passes,
+                scratch,
+                &target.color,
+                size,
+                layer.blur,
+            );
+        }
+    }
+}
+
+/// Begins a pass drawing into `view`. With MSAA the pass draws into `msaa_texture` instead
+/// and resolves it into `view`, so the same multisampled texture serves every pass.
+fn begin_render_pass<'a>(
+    encoder: &'a mut wgpu::CommandEncoder,
+    view: &'a wgpu::TextureView,
+    msaa_texture: Option<&'a Texture>,
+    depth_view: &'a wgpu::TextureView,
+    clear: wgpu::Color,
+) -> wgpu::RenderPass<'a> {
+    let (view, resolve_target) = match msaa_texture {
+        Some(msaa_texture) => (&msaa_texture.view, Some(view)),
+        None => (view, None),
+    };
+    encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some("Render Pass"),
+        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+            view,
+            resolve_target,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Clear(clear),
+                store: wgpu::StoreOp::Store,
+            },
+        })],
+        depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+            view: depth_view,
+            depth_ops: Some(wgpu::Operations {
+                load: wgpu::LoadOp::Clear(1.0),
+                store: wgpu::StoreOp::Store,
+            }),
+            stencil_ops: Some(wgpu::Operations {
+                load: wgpu::LoadOp::Clear(0),
+                store: wgpu::StoreOp::Store,
+            }),
+        }),
+        occlusion_query_set: None,
+        timestamp_writes: None,
+    })
+}
+
+/// Returns the highest sample count up to `requested` that the adapter supports for both
+/// `format` and the depth-stencil format. Sample counts other than 1 and 4 also require
+/// `wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES` on the device.
+pub fn supported_sample_count(
+    adapter: &wgpu::Adapter,
+    format: wgpu::TextureFormat,
+    requested: u32,
+) -> u32 {
+    let color_flags = adapter.get_texture_format_features(format).flags;
+    let depth_flags = adapter
+        .get_texture_format_features(Texture::DEPTH_FORMAT)
+        .flags;
+    [8, 4, 2]
+        .into_iter()
+        .find(|&count| {
+            count <= requested
+                && color_flags.sample_count_supported(count)
+                && depth_flags.sample_count_supported(count)
+        })
+        .unwrap_or(1)
+}
+
+fn create_msaa_texture(
+    device: &Device,
+    config: &wgpu::SurfaceConfiguration,
+    sample_count: u32,
+) -> Option<Texture> {
+    (sample_count > 1)
+        .then(|| Texture::create_multisampled_target(device, config, sample_count, "msaa_texture"))
+}
@@ -0,0 +1,47 @@
+struct BlurUniform {
+	// One texel along the blur direction, in texture coordinates.
+	direction: vec2<f32>,
+	sigma: f32,
+	radius: i32,
+	// The last texel center of the blurred area, which may be smaller than the texture.
+	max_coords: vec2<f32>,
+}
+
+struct VertexOutput {
+	@builtin(position) clip_position: vec4<f32>,
+	@location(0) tex_coords: vec2<f32>,
+}
+
+// A single triangle covering the whole target.
+@vertex
+fn vs_main(
+	@builtin(vertex_index) index: u32,
+) -> VertexOutput {
+	let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+	var out: VertexOutput;
+	out.tex_coords = uv;
+	out.clip_position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+	return out;
+}
+
+@group(0) @binding(0)
+var t_source: texture_2d<f32>;
+@group(0) @binding(1)
+var s_source: sampler;
+@group(0) @binding(2)
+var<uniform> blur: BlurUniform;
+
+// One direction of a separable gaussian blur.
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+	var color = vec4<f32>(0.0);
+	var total = 0.0;
+	for (var i = -blur.radius; i <= blur.radius; i++) {
+		let x = f32(i);
+		let weight = exp(-(x * x) / (2.0 * blur.sigma * blur.sigma));
+		let coords = min(in.tex_coords + blur.direction * x, blur.max_coords);
+		color += textureSample(t_source, s_source, coords) * weight;
+		total += weight;
+	}
+	return color / total;
+}
@@ -0,0 +1,97 @@
+struct LayerUniform {
+	opacity: f32,
+	// 0: no mask, 1: alpha mask, 2: luminance mask
+	mask_mode: u32,
+	// In pixels.
+	shadow_offset: vec2<f32>,
+	shadow_color: vec4<f32>,
+	// The screen rect of the layer in pixels: x, y, width and height.
+	rect: vec4<f32>,
+	// The screen position and texture size of the target the layer is composited into.
+	parent_origin: vec2<f32>,
+	parent_size: vec2<f32>,
+	// The screen rect of the mask layer.
+	mask_origin: vec2<f32>,
+	mask_size: vec2<f32>,
+}
+
+struct VertexOutput {
+	@builtin(position) clip_position: vec4<f32>,
+	@location(0) tex_coords: vec2<f32>,
+	// The screen position in pixels, where the mask is sampled.
+	@location(1) screen: vec2<f32>,
+}
+
+// A quad covering the layer rect moved by `offset` pixels, in two triangles.
+fn quad(index: u32, offset: vec2<f32>) -> VertexOutput {
+	var corners = array<vec2<f32>, 6>(
+		vec2<f32>(0.0, 0.0),
+		vec2<f32>(1.0, 0.0),
+		vec2<f32>(0.0, 1.0),
+		vec2<f32>(0.0, 1.0),
+		vec2<f32>(1.0, 0.0),
+		vec2<f32>(1.0, 1.0),
+	);
+	let corner = corners[index];
+	let screen = layer.rect.xy + corner * layer.rect.zw + offset;
+	let position = (screen - layer.parent_origin) / layer.parent_size;
+	var out: VertexOutput;
+	out.tex_coords = corner * layer.rect.zw / vec2<f32>(textureDimensions(t_layer));
+	out.screen = screen;
+	out.clip_position = vec4<f32>(position.x * 2.0 - 1.0, 1.0 - position.y * 2.0, 0.0, 1.0);
+	return out;
+}
+
+@vertex
+fn vs_main(
+	@builtin(vertex_index) index: u32,
+) -> VertexOutput {
+	return quad(index, vec2<f32>(0.0));
+}
+
+// The shadow is the blurred layer drawn `shadow_offset` pixels away.
+@vertex
+fn vs_shadow(
+	@builtin(vertex_index) index: u32,
+) -> VertexOutput {
+	return quad(index, layer.shadow_offset);
+}
+
+@group(0) @binding(0)
+var t_layer: texture_2d<f32>;
+@group(0) @binding(1)
+var s_layer: sampler;
+@group(0) @binding(2)
+var t_mask: texture_2d<f32>;
+@group(0) @binding(3)
+var<uniform> layer: LayerUniform;
+
+fn coverage(screen: vec2<f32>) -> f32 {
+	// Sampled everywhere since textureSample needs uniform control flow, outside the mask
+	// rect nothing was drawn into the mask.
+	let mask_coords = (screen - layer.mask_origin) / vec2<f32>(textureDimensions(t_mask));
+	let inside = all(screen >= layer.mask_origin) && all(screen < layer.mask_origin + layer.mask_size);
+	let mask = select(vec4<f32>(0.0), textureSample(t_mask, s_layer, mask_coords), inside);
+	var result = layer.opacity;
+	if layer.mask_mode == 1u {
+		result *= mask.a;
+	} else if layer.mask_mode == 2u {
+		result *= dot(mask.rgb, vec3<f32>(0.2126, 0.7152, 0.0722)) * mask.a;
+	}
+	return result;
+}
+
+// Layer targets hold premultiplied colors, since shapes are blended into a transparent target.
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+	let color = textureSample(t_layer, s_layer, in.tex_coords);
+	return color * coverage(in.screen);
+}
+
+// Here t_layer is the blurred copy of the layer.
+@fragment
+fn fs_shadow(in: VertexOutput) -> @location(0) vec4<f32> {
+	let alpha = textureSample(t_layer, s_layer, in.tex_coords).a;
+	let shadow_alpha = layer.shadow_color.a * alpha * coverage(in.screen);
+	return vec4<f32>(layer.shadow_color.rgb * shadow_alpha, shadow_alpha);
+}
@@ -0,0 +1,61 @@
+// Put in front of the source of every material, which has to define
+//
+//     fn material(in: MaterialInput) -> vec4<f32>
+//
+// returning the color of a fragment, and may declare its uniforms as
+//
+//     @group(0) @binding(0) var<uniform> uniforms: MyUniforms;
+
+struct VertexInput {
+	@location(0) color: vec4<f32>,
+	@location(1) position: vec3<f32>,
+	@location(2) edge: vec2<f32>,
+	@location(3) uv: vec2<f32>,
+}
+
+struct View {
+	scale: vec2<f32>,
+	offset: vec2<f32>,
+}
+
+@group(1) @binding(0)
+var<uniform> view: View;
+
+struct MaterialInput {
+	// The framebuffer position of the fragment in pixels, from the top left.
+	@builtin(position) frag_position: vec4<f32>,
+	// The color of the shape, see `Canvas::set_color`.
+	@location(0) color: vec4<f32>,
+	// The canvas position, from (-1, -1) at the bottom left to (1, 1) at the top right.
+	@location(1) position: vec2<f32>,
+	// The position inside the bounds of the path, from (0, 0) at the top left to (1, 1)
+	// at the bottom right.
+	@location(2) uv: vec2<f32>,
+	// Used to feather strokes after `material`.
+	@location(3) edge: vec2<f32>,
+}
+
+@vertex
+fn vs_main(
+	model: VertexInput
+) -> MaterialInput {
+	var out: MaterialInput;
+	out.color = model.color;
+	out.position = model.position.xy;
+	out.uv = model.uv;
+	out.edge = model.edge;
+	out.frag_position = vec4<f32>(model.position.xy * view.scale + view.offset, model.position.z, 1.0);
+	return out;
+}
+
+@fragment
+fn fs_main(in: MaterialInput) -> @location(0) vec4<f32> {
+	var color = material(in);
+	if in.edge.y > 0.0 {
+		let distance = 1.0 - abs(in.edge.x);
+		color.a *= clamp(distance / in.edge.y, 0.0, 1.0);
+	}
+	return color;
+}
+
+// The material follows.
@@ -1,11 +1,22 @@
 struct VertexInput {
 	@location(0) color: vec4<f32>,
 	@location(1) position: vec3<f32>,
+	@location(2) edge: vec2<f32>,
 }
 
+// Moves canvas coordinates into the target of the render pass, see `View`.
+struct View {
+	scale: vec2<f32>,
+	offset: vec2<f32>,
+}
+
+@group(0) @binding(0)
+var<uniform> view: View;
+
 struct VertexOutput {
 	@builtin(position) clip_position: vec4<f32>,
 	@location(0) color: vec4<f32>,
+	@location(1) edge: vec2<f32>,
 }
 
 @vertex
@@ -14,12 +25,20 @@ fn vs_main(
 ) -> VertexOutput {
 	var out: VertexOutput;
 	out.color = model.color;
-	out.clip_position = vec4<f32>(model.position, 1.0);
+	out.edge = model.edge;
+	out.clip_position = vec4<f32>(model.position.xy * view.scale + view.offset, model.position.z, 1.0);
 	return out;
 }
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
 	// return vec4<f32>(1.0, 0.0, 1.0, 1.0);
-	return in.color;
+	var color = in.color;
+	if in.edge.y > 0.0 {
+		// Fades out across the whole fringe, from the edge of the stroke to the outer edge
+		// of the fringe.
+		let distance = 1.0 - abs(in.edge.x);
+		color.a *= clamp(distance / in.edge.y, 0.0, 1.0);
+	}
+	return color;
 }
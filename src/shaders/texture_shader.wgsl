@@ -8,11 +8,31 @@ struct InstanceInput {
     @location(6) model_matrix_1: vec4<f32>,
     @location(7) model_matrix_2: vec4<f32>,
     @location(8) model_matrix_3: vec4<f32>,
+    // The drawn part of the texture: offset in xy, size in zw.
+    @location(9) uv_rect: vec4<f32>,
+    @location(10) color: vec4<f32>,
+    // The value mapping of data textures: min, max and the mode, which is 0 without a
+    // mapping, 1 for color, 2 for gray and 3 for a colormap lookup.
+    @location(11) mapping: vec4<f32>,
+    // Drawn for NaN values in colormap mode.
+    @location(12) nan_color: vec4<f32>,
 }
 
+// Moves canvas coordinates into the target of the render pass, see `View`.
+struct View {
+    scale: vec2<f32>,
+    offset: vec2<f32>,
+}
+
+@group(2) @binding(0)
+var<uniform> view: View;
+
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) tex_coords: vec2<f32>,
+    @location(1) color: vec4<f32>,
+    @location(2) @interpolate(flat) mapping: vec4<f32>,
+    @location(3) @interpolate(flat) nan_color: vec4<f32>,
 }
 
 @vertex
@@ -27,8 +47,15 @@ fn vs_main(
         instance.model_matrix_3,
     );
     var out: VertexOutput;
-    out.tex_coords = model.tex_coords;
-	out.clip_position = vec4<f32>(model.position, 1.0);
+    out.tex_coords = instance.uv_rect.xy + model.tex_coords * instance.uv_rect.zw;
+    out.color = instance.color;
+    out.mapping = instance.mapping;
+    out.nan_color = instance.nan_color;
+    let position = model_matrix * vec4<f32>(model.position, 1.0);
+    out.clip_position = vec4<f32>(
+        position.xy * view.scale + view.offset * position.w,
+        position.zw,
+    );
     return out;
 }
 
@@ -39,7 +66,43 @@ var t_diffuse: texture_2d<f32>;
 @group(0)@binding(1)
 var s_diffuse: sampler;
 
+// A one texel high lookup table from the lowest value on the left to the highest.
+@group(1) @binding(0)
+var t_colormap: texture_2d<f32>;
+@group(1) @binding(1)
+var s_colormap: sampler;
+
+// Compares the bits, since comparisons with NaN may be optimized away.
+fn is_nan(value: f32) -> bool {
+    return (bitcast<u32>(value) & 0x7fffffffu) > 0x7f800000u;
+}
+
+// Mapped values are the displayed brightness, so they are decoded like the colors of
+// sRGB textures.
+fn srgb_to_linear(color: vec3<f32>) -> vec3<f32> {
+    let low = color / 12.92;
+    let high = pow((color + 0.055) / 1.055, vec3<f32>(2.4));
+    return select(high, low, color <= vec3<f32>(0.04045));
+}
+
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    var color = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    let mode = in.mapping.z;
+    if mode > 0.5 {
+        let range = max(in.mapping.y - in.mapping.x, 1e-20);
+        let value = clamp((color - in.mapping.x) / range, vec4<f32>(0.0), vec4<f32>(1.0));
+        if mode > 2.5 {
+            // Samples the centers of the first and last texels for the ends of the range.
+            let size = f32(textureDimensions(t_colormap).x);
+            let u = (value.r * (size - 1.0) + 0.5) / size;
+            let mapped = textureSampleLevel(t_colormap, s_colormap, vec2<f32>(u, 0.5), 0.0);
+            color = select(mapped, in.nan_color, is_nan(color.r));
+        } else if mode > 1.5 {
+            color = vec4<f32>(srgb_to_linear(value.rrr), 1.0);
+        } else {
+            color = vec4<f32>(srgb_to_linear(value.rgb), color.a);
+        }
+    }
+    return color * in.color;
 }
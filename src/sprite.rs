@@ -0,0 +1,552 @@
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use lyon::math::{point, vector, Angle, Box2D, Point, Transform, Vector};
+use serde::Deserialize;
+use serde_json::Value;
+use wgpu::{Device, Queue};
+
+use crate::canvas::{Canvas, ShapeId};
+use crate::store::{TextureHandle, TextureStore};
+use crate::texture::SamplerOptions;
+
+/// A rectangle of a texture placed by its center, drawn as one instance of a
+/// `SpriteBatch`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sprite {
+    /// Where the center of the sprite goes, in canvas coordinates.
+    pub position: Point,
+    /// Counterclockwise, in radians.
+    pub rotation: f32,
+    /// The width and height of the sprite in canvas units.
+    pub scale: Vector,
+    /// The part of the texture that is drawn, in texture coordinates from 0 to 1 with y
+    /// pointing down.
+    pub uv: Box2D,
+    /// Multiplied with the texture color and the color of the batch shape.
+    pub color: [f32; 4],
+}
+
+impl Sprite {
+    /// A sprite of the whole texture, `width` by `height` canvas units large.
+    pub fn new(x: f32, y: f32, width: f32, height: f32) -> Sprite {
+        Sprite {
+            position: point(x, y),
+            rotation: 0.0,
+            scale: vector(width, height),
+            uv: Box2D::new(point(0.0, 0.0), point(1.0, 1.0)),
+            color: [1.0; 4],
+        }
+    }
+
+    /// Places the unit square, which the texture pipeline draws, on the sprite.
+    pub fn transform(&self) -> Transform {
+        Transform::translation(-0.5, -0.5)
+            .then_scale(self.scale.x, self.scale.y)
+            .then_rotate(Angle::radians(self.rotation))
+            .then_translate(self.position.to_vector())
+    }
+
+    /// Whether `point` is on the sprite, or within `tolerance` canvas units of it.
+    pub fn contains(&self, point: Point, tolerance: f32) -> bool {
+        let Some(inverse) = self.transform().inverse() else {
+            return false;
+        };
+        let point = inverse.transform_point(point);
+        let (x, y) = (
+            tolerance / self.scale.x.abs(),
+            tolerance / self.scale.y.abs(),
+        );
+        (-x..=1.0 + x).contains(&point.x) && (-y..=1.0 + y).contains(&point.y)
+    }
+
+    /// The axis aligned bounds of the rotated sprite.
+    pub fn bounds(&self) -> Box2D {
+        let transform = self.transform();
+        Box2D::from_points(
+            [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)]
+                .map(|(x, y)| transform.transform_point(point(x, y))),
+        )
+    }
+}
+
+/// Many sprites of one texture, drawn with `Canvas::draw_sprites` as a single shape whose
+/// sprites are instances of one draw call. Meant to be refilled every frame, see
+/// `Canvas::set_sprites`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpriteBatch {
+    pub texture: TextureHandle,
+    pub sprites: Vec<Sprite>,
+}
+
+impl SpriteBatch {
+    pub fn new(texture: &TextureHandle) -> SpriteBatch {
+        SpriteBatch {
+            texture: texture.clone(),
+            sprites: vec![],
+        }
+    }
+
+    pub fn push(&mut self, sprite: Sprite) {
+        self.sprites.push(sprite);
+    }
+
+    /// Removes the sprites, keeping their memory for the next frame.
+    pub fn clear(&mut self) {
+        self.sprites.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.sprites.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sprites.is_empty()
+    }
+
+    /// The bounds of all sprites, empty at the origin without sprites.
+    pub fn bounds(&self) -> Box2D {
+        self.sprites
+            .iter()
+            .map(Sprite::bounds)
+            .reduce(|a, b| a.union(&b))
+            .unwrap_or_default()
+    }
+}
+
+/// One image of a `SpriteSheet`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpriteFrame {
+    /// Where the frame is on the sheet, in texture coordinates from 0 to 1 with y down.
+    pub uv: Box2D,
+    /// The size of the frame in pixels.
+    pub size: (u32, u32),
+    /// How long the frame is shown by an `AnimatedSprite`.
+    pub duration: Duration,
+}
+
+/// The order in which an `AnimatedSprite` plays the frames of an `Animation`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    #[default]
+    Forward,
+    Reverse,
+    /// Forward, then back to the start without repeating the last and first frames.
+    #[serde(rename = "pingpong")]
+    PingPong,
+    #[serde(rename = "pingpong_reverse")]
+    PingPongReverse,
+}
+
+/// A named run of frames of a `SpriteSheet`, a frame tag in Aseprite.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Animation {
+    pub frames: RangeInclusive<usize>,
+    pub direction: Direction,
+}
+
+impl Animation {
+    /// The frames one cycle of the animation shows, in order.
+    pub fn sequence(&self) -> Vec<usize> {
+        let forward: Vec<usize> = self.frames.clone().collect();
+        let mut reverse = forward.clone();
+        reverse.reverse();
+        let bounce = |there: Vec<usize>, back: &[usize]| {
+            let back = back
+                .get(1..back.len().saturating_sub(1))
+                .unwrap_or_default();
+            [there.as_slice(), back].concat()
+        };
+        match self.direction {
+            Direction::Forward => forward,
+            Direction::Reverse => reverse,
+            Direction::PingPong => bounce(forward, &reverse),
+            Direction::PingPongReverse => bounce(reverse, &forward),
+        }
+    }
+}
+
+/// The frames of animations packed into one texture.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpriteSheet {
+    pub texture: TextureHandle,
+    pub frames: Vec<SpriteFrame>,
+    pub animations: HashMap<String, Animation>,
+}
+
+impl SpriteSheet {
+    /// Cuts the whole texture into `columns` by `rows` frames of equal size, numbered row
+    /// by row from the top left, each shown for `duration`. Fails when that leaves no frames
+    /// or frames without pixels.
+    pub fn from_grid(
+        texture: &TextureHandle,
+        columns: u32,
+        rows: u32,
+        duration: Duration,
+    ) -> Result<SpriteSheet> {
+        let (width, height) = texture.size();
+        if columns == 0 || rows == 0 || width < columns || height < rows {
+            bail!("cannot cut a {width}x{height} texture into {columns}x{rows} frames");
+        }
+        let size = (width / columns, height / rows);
+        let frames = (0..rows)
+            .flat_map(|row| (0..columns).map(move |column| (column, row)))
+            .map(|(column, row)| SpriteFrame {
+                uv: pixel_uv(texture, column * size.0, row * size.1, size),
+                size,
+                duration,
+            })
+            .collect();
+        Ok(SpriteSheet {
+            texture: texture.clone(),
+            frames,
+            animations: HashMap::new(),
+        })
+    }
+
+    /// Reads the descriptor at `path` and loads the image it names, which is looked up
+    /// next to the descriptor. See `from_json` for the formats.
+    pub fn load(
+        device: &Device,
+        queue: &Queue,
+        textures: &mut TextureStore,
+        path: impl AsRef<Path>,
+        sampler: &SamplerOptions,
+    ) -> Result<SpriteSheet> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read sprite sheet {}", path.display()))?;
+        let descriptor: Value = serde_json::from_str(&json)
+            .with_context(|| format!("invalid sprite sheet {}", path.display()))?;
+        let image = descriptor
+            .pointer("/meta/image")
+            .or_else(|| descriptor.get("image"))
+            .and_then(Value::as_str)
+            .with_context(|| format!("sprite sheet {} names no image", path.display()))?;
+        let image = path.parent().unwrap_or(Path::new("")).join(image);
+        let texture = textures.load_path(device, queue, &image, sampler)?;
+        SpriteSheet::from_json(&texture, &json)
+            .with_context(|| format!("invalid sprite sheet {}", path.display()))
+    }
+
+    /// Reads the frames of `texture` from a JSON descriptor, either a sheet exported by
+    /// Aseprite, with the frames as an array or a hash, or a grid:
+    ///
+    /// ```json
+    /// { "image": "walk.png", "columns": 8, "rows": 2, "duration": 100,
+    ///   "frameTags": [{ "name": "walk", "from": 0, "to": 7, "direction": "forward" }] }
+    /// ```
+    ///
+    /// Durations are in milliseconds, the frame tags become the `animations`. Sheets need
+    /// at least one frame.
+    pub fn from_json(texture: &TextureHandle, json: &str) -> Result<SpriteSheet> {
+        let descriptor: Value = serde_json::from_str(json)?;
+        let (mut sheet, tags) = if descriptor.get("frames").is_some() {
+            let descriptor: AsepriteDescriptor = serde_json::from_value(descriptor)?;
+            let frames = match descriptor.frames {
+                Value::Array(frames) => frames,
+                // Kept in file order, which is the frame order.
+                Value::Object(frames) => frames.into_iter().map(|(_, frame)| frame).collect(),
+                _ => bail!("frames must be an array or an object"),
+            };
+            let frames = frames
+                .into_iter()
+                .map(|frame| {
+                    let frame: AsepriteFrame = serde_json::from_value(frame)?;
+                    let rect = frame.frame;
+                    Ok(SpriteFrame {
+                        uv: pixel_uv(texture, rect.x, rect.y, (rect.w, rect.h)),
+                        size: (rect.w, rect.h),
+                        duration: Duration::from_millis(frame.duration),
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            if frames.is_empty() {
+                bail!("the sprite sheet has no frames");
+            }
+            let sheet = SpriteSheet {
+                texture: texture.clone(),
+                frames,
+                animations: HashMap::new(),
+            };
+            (sheet, descriptor.meta.frame_tags)
+        } else {
+            let grid: GridDescriptor = serde_json::from_value(descriptor)?;
+            let duration = Duration::from_millis(grid.duration);
+            let sheet = SpriteSheet::from_grid(texture, grid.columns, grid.rows, duration)?;
+            (sheet, grid.frame_tags)
+        };
+        for tag in tags {
+            if tag.from > tag.to || tag.to >= sheet.frames.len() {
+                bail!(
+                    "frame tag {} spans frames {} to {} of {}",
+                    tag.name,
+                    tag.from,
+                    tag.to,
+                    sheet.frames.len()
+                );
+            }
+            let animation = Animation {
+                frames: tag.from..=tag.to,
+                direction: tag.direction,
+            };
+            sheet.animations.insert(tag.name, animation);
+        }
+        Ok(sheet)
+    }
+
+    /// The animation of all frames in order.
+    pub fn all_frames(&self) -> Animation {
+        Animation {
+            frames: 0..=self.frames.len().saturating_sub(1),
+            direction: Direction::Forward,
+        }
+    }
+}
+
+/// The texture coordinates of a `size` pixels large area of `texture` at `x`, `y`.
+fn pixel_uv(texture: &TextureHandle, x: u32, y: u32, size: (u32, u32)) -> Box2D {
+    let (width, height) = texture.size();
+    let (width, height) = (width.max(1) as f32, height.max(1) as f32);
+    Box2D::new(
+        point(x as f32 / width, y as f32 / height),
+        point((x + size.0) as f32 / width, (y + size.1) as f32 / height),
+    )
+}
+
+#[derive(Deserialize)]
+struct AsepriteDescriptor {
+    frames: Value,
+    #[serde(default)]
+    meta: AsepriteMeta,
+}
+
+#[derive(Deserialize, Default)]
+struct AsepriteMeta {
+    #[serde(default, rename = "frameTags")]
+    frame_tags: Vec<FrameTag>,
+}
+
+#[derive(Deserialize)]
+struct AsepriteFrame {
+    frame: PixelRect,
+    #[serde(default = "default_duration")]
+    duration: u64,
+}
+
+#[derive(Deserialize)]
+struct PixelRect {
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+}
+
+#[derive(Deserialize)]
+struct GridDescriptor {
+    columns: u32,
+    rows: u32,
+    #[serde(default = "default_duration")]
+    duration: u64,
+    #[serde(default, rename = "frameTags")]
+    frame_tags: Vec<FrameTag>,
+}
+
+#[derive(Deserialize)]
+struct FrameTag {
+    name: String,
+    from: usize,
+    to: usize,
+    #[serde(default)]
+    direction: Direction,
+}
+
+/// Aseprite's default frame duration, in milliseconds.
+fn default_duration() -> u64 {
+    100
+}
+
+/// Plays an animation of a `SpriteSheet` as time passes.
+#[derive(Debug, Clone)]
+pub struct AnimatedSprite {
+    pub sheet: Arc<SpriteSheet>,
+    /// Multiplies the time passed to `update`.
+    pub speed: f32,
+    /// Starts the animation over after its last frame, instead of stopping there.
+    pub looping: bool,
+    sequence: Vec<usize>,
+    step: usize,
+    /// The time the current frame has been shown.
+    elapsed: Duration,
+}
+
+impl AnimatedSprite {
+    /// Loops over all frames of `sheet`, which needs at least one.
+    pub fn new(sheet: Arc<SpriteSheet>) -> AnimatedSprite {
+        let sequence = sheet.all_frames().sequence();
+        AnimatedSprite {
+            sheet,
+            speed: 1.0,
+            looping: true,
+            sequence,
+            step: 0,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    /// Starts the animation `name` of the sheet from its first frame. Returns false,
+    /// leaving the current animation playing, when the sheet has no such animation.
+    pub fn play(&mut self, name: &str) -> bool {
+        let Some(animation) = self.sheet.animations.get(name) else {
+            return false;
+        };
+        self.sequence = animation.sequence();
+        self.step = 0;
+        self.elapsed = Duration::ZERO;
+        true
+    }
+
+    /// Advances the animation by `dt`, skipping frames when more than one has passed.
+    /// Frames without a duration hold the animation.
+    pub fn update(&mut self, dt: Duration) {
+        self.elapsed += dt.mul_f32(self.speed.max(0.0));
+        loop {
+            let duration = self.current().duration;
+            if duration.is_zero() || self.elapsed < duration || self.is_finished() {
+                break;
+            }
+            self.elapsed -= duration;
+            self.step = (self.step + 1) % self.sequence.len();
+        }
+    }
+
+    /// Whether an animation that does not loop has shown its last frame to the end.
+    pub fn is_finished(&self) -> bool {
+        !self.looping
+            && self.step + 1 == self.sequence.len()
+            && self.elapsed >= self.current().duration
+    }
+
+    /// The index of the shown frame in the frames of the sheet.
+    pub fn frame_index(&self) -> usize {
+        self.sequence.get(self.step).copied().unwrap_or(0)
+    }
+
+    pub fn current(&self) -> SpriteFrame {
+        self.sheet.frames[self.frame_index()]
+    }
+
+    /// Draws the current frame stretched over `rect`.
+    pub fn draw(&self, canvas: &mut Canvas, rect: Box2D) -> ShapeId {
+        canvas.draw_texture_region(&self.sheet.texture, self.current().uv, rect)
+    }
+
+    /// The current frame as a sprite centered on `x`, `y`, for a `SpriteBatch` of the
+    /// texture of the sheet.
+    pub fn sprite(&self, x: f32, y: f32, width: f32, height: f32) -> Sprite {
+        Sprite {
+            uv: self.current().uv,
+            ..Sprite::new(x, y, width, height)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequence(frames: RangeInclusive<usize>, direction: Direction) -> Vec<usize> {
+        Animation { frames, direction }.sequence()
+    }
+
+    #[test]
+    fn ping_pong_sequences_skip_the_turning_frames() {
+        assert_eq!(sequence(0..=3, Direction::PingPong), vec![0, 1, 2, 3, 2, 1]);
+        assert_eq!(
+            sequence(2..=4, Direction::PingPongReverse),
+            vec![4, 3, 2, 3]
+        );
+        assert_eq!(sequence(0..=1, Direction::PingPong), vec![0, 1]);
+        assert_eq!(sequence(5..=5, Direction::PingPong), vec![5]);
+        assert_eq!(sequence(1..=3, Direction::Reverse), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn reads_aseprite_frame_hashes_and_tags() {
+        let texture = TextureHandle::detached(64, 32);
+        let json = r#"{
+            "frames": {
+                "walk 0.aseprite": {
+                    "frame": { "x": 0, "y": 0, "w": 32, "h": 32 },
+                    "duration": 50
+                },
+                "walk 1.aseprite": { "frame": { "x": 32, "y": 0, "w": 32, "h": 16 } }
+            },
+            "meta": {
+                "image": "walk.png",
+                "frameTags": [
+                    { "name": "walk", "from": 0, "to": 1, "direction": "pingpong" }
+                ]
+            }
+        }"#;
+        let sheet = SpriteSheet::from_json(&texture, json).unwrap();
+
+        assert_eq!(sheet.frames.len(), 2);
+        assert_eq!(sheet.frames[0].duration, Duration::from_millis(50));
+        assert_eq!(sheet.frames[1].duration, Duration::from_millis(100));
+        assert_eq!(sheet.frames[1].size, (32, 16));
+        assert_eq!(
+            sheet.frames[1].uv,
+            Box2D::new(point(0.5, 0.0), point(1.0, 0.5))
+        );
+        assert_eq!(
+            sheet.animations["walk"],
+            Animation {
+                frames: 0..=1,
+                direction: Direction::PingPong,
+            }
+        );
+    }
+
+    #[test]
+    fn reads_aseprite_frame_arrays() {
+        let texture = TextureHandle::detached(16, 16);
+        let json = r#"{ "frames": [
+            { "frame": { "x": 0, "y": 0, "w": 8, "h": 8 }, "duration": 20 },
+            { "frame": { "x": 8, "y": 8, "w": 8, "h": 8 }, "duration": 30 }
+        ] }"#;
+        let sheet = SpriteSheet::from_json(&texture, json).unwrap();
+
+        let durations: Vec<u128> = sheet
+            .frames
+            .iter()
+            .map(|frame| frame.duration.as_millis())
+            .collect();
+        assert_eq!(durations, vec![20, 30]);
+        assert!(sheet.animations.is_empty());
+    }
+
+    #[test]
+    fn rejects_sheets_without_frames() {
+        let texture = TextureHandle::detached(16, 16);
+        assert!(SpriteSheet::from_json(&texture, r#"{ "frames": [] }"#).is_err());
+        assert!(SpriteSheet::from_json(&texture, r#"{ "frames": {} }"#).is_err());
+        assert!(SpriteSheet::from_grid(&texture, 0, 1, Duration::ZERO).is_err());
+        assert!(SpriteSheet::from_grid(&texture, 17, 1, Duration::ZERO).is_err());
+        let grid = r#"{ "columns": 4, "rows": 0 }"#;
+        assert!(SpriteSheet::from_json(&texture, grid).is_err());
+    }
+
+    #[test]
+    fn rejects_tags_outside_the_frames() {
+        let texture = TextureHandle::detached(16, 16);
+        let json = r#"{ "columns": 2, "rows": 2,
+            "frameTags": [{ "name": "all", "from": 0, "to": 4 }] }"#;
+        assert!(SpriteSheet::from_json(&texture, json).is_err());
+    }
+}
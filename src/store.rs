@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Weak};
+
+use anyhow::Result;
+use wgpu::{Device, Queue};
+
+use crate::texture::{MipmapGenerator, SamplerOptions, Texture};
+
+/// Identifies a texture of a `TextureStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TextureId(u64);
+
+/// A cheap reference to a texture of a `TextureStore`. The texture is freed once the last
+/// clone of its handle is dropped, including those held by shapes of a `Canvas`.
+#[derive(Debug, Clone)]
+pub struct TextureHandle(Arc<HandleInner>);
+
+#[derive(Debug)]
+struct HandleInner {
+    id: TextureId,
+    /// Changed when the texture is reloaded with another size.
+    width: AtomicU32,
+    height: AtomicU32,
+}
+
+impl TextureHandle {
+    pub fn id(&self) -> TextureId {
+        self.0.id
+    }
+
+    /// The size of the texture in pixels.
+    pub fn size(&self) -> (u32, u32) {
+        (
+            self.0.width.load(Ordering::Relaxed),
+            self.0.height.load(Ordering::Relaxed),
+        )
+    }
+
+    /// A handle of the given size that belongs to no store.
+    #[cfg(test)]
+    pub(crate) fn detached(width: u32, height: u32) -> TextureHandle {
+        TextureHandle(Arc::new(HandleInner {
+            id: TextureId(u64::MAX),
+            width: AtomicU32::new(width),
+            height: AtomicU32::new(height),
+        }))
+    }
+}
+
+impl PartialEq for TextureHandle {
+    fn eq(&self, other: &TextureHandle) -> bool {
+        self.id() == other.id()
+    }
+}
+
+impl Eq for TextureHandle {}
+
+/// Owns the textures drawn by the renderer together with their bind groups, which are
+/// created once when a texture is added.
+pub struct TextureStore {
+    /// The layout of the bind groups: the texture view at binding 0, its sampler at 1.
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    /// The same for textures whose format cannot be filtered, like 32 bit floats on most
+    /// devices.
+    pub unfilterable_bind_group_layout: wgpu::BindGroupLayout,
+    entries: HashMap<TextureId, StoreEntry>,
+    /// The textures loaded with `load_path`, so loading a file again with the same options
+    /// shares the texture.
+    paths: HashMap<(PathBuf, SamplerOptions), TextureId>,
+    next_id: u64,
+    /// Shared by all loaded textures with mipmaps.
+    mipmaps: MipmapGenerator,
+}
+
+struct StoreEntry {
+    texture: Texture,
+    bind_group: wgpu::BindGroup,
+    filterable: bool,
+    handle: Weak<HandleInner>,
+    path: Option<(PathBuf, SamplerOptions)>,
+}
+
+impl TextureStore {
+    pub fn new(device: &Device) -> TextureStore {
+        TextureStore {
+            bind_group_layout: create_bind_group_layout(device, true),
+            unfilterable_bind_group_layout: create_bind_group_layout(device, false),
+            entries: HashMap::new(),
+            paths: HashMap::new(),
+            next_id: 0,
+            mipmaps: MipmapGenerator::default(),
+        }
+    }
+
+    /// Loads the image file at `path`. While a handle to an earlier load of the same path
+    /// with the same options is alive, its texture is returned instead of reading the file
+    /// again.
+    pub fn load_path(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        path: impl AsRef<Path>,
+        sampler: &SamplerOptions,
+    ) -> Result<TextureHandle> {
+        let key = (path.as_ref().to_path_buf(), *sampler);
+        if let Some(handle) = self
+            .paths
+            .get(&key)
+            .and_then(|id| self.entries[id].handle.upgrade())
+        {
+            return Ok(TextureHandle(handle));
+        }
+        let bytes = std::fs::read(&key.0)?;
+        let label = key.0.to_string_lossy();
+        let texture = self.decode(device, queue, &bytes, &label, sampler)?;
+        let handle = self.insert(device, texture);
+        self.paths.insert(key.clone(), handle.id());
+        self.entries.get_mut(&handle.id()).unwrap().path = Some(key);
+        Ok(handle)
+    }
+
+    /// Decodes an image file that is already in memory.
+    pub fn load_bytes(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        bytes: &[u8],
+        label: &str,
+        sampler: &SamplerOptions,
+    ) -> Result<TextureHandle> {
+        let texture = self.decode(device, queue, bytes, label, sampler)?;
+        Ok(self.insert(device, texture))
+    }
+
+    fn decode(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        bytes: &[u8],
+        label: &str,
+        sampler: &SamplerOptions,
+    ) -> Result<Texture> {
+        let image = image::load_from_memory(bytes)?;
+        Texture::from_image_with(
+            device,
+            queue,
+            &image,
+            Some(label),
+            sampler,
+            &mut self.mipmaps,
+        )
+    }
+
+    /// Adds a texture created by the caller.
+    pub fn insert(&mut self, device: &Device, texture: Texture) -> TextureHandle {
+        let id = TextureId(self.next_id);
+        self.next_id += 1;
+        let size = texture.texture.size();
+        let handle = Arc::new(HandleInner {
+            id,
+            width: AtomicU32::new(size.width),
+            height: AtomicU32::new(size.height),
+        });
+        let filterable = is_filterable(device, &texture);
+        let bind_group = self.create_bind_group(device, &texture, filterable);
+        self.entries.insert(
+            id,
+            StoreEntry {
+                texture,
+                bind_group,
+                filterable,
+                handle: Arc::downgrade(&handle),
+                path: None,
+            },
+        );
+        TextureHandle(handle)
+    }
+
+    /// The files loaded with `load_path` whose textures are still held.
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.paths.keys().map(|(path, _)| path.as_path())
+    }
+
+    /// Decodes the file `path` again for every texture loaded from it with `load_path`,
+    /// keeping their ids so shapes and handles show the new image. Returns whether any
+    /// texture was loaded from `path`.
+    pub fn reload_path(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        path: impl AsRef<Path>,
+    ) -> Result<bool> {
+        let path = path.as_ref();
+        let keys: Vec<(PathBuf, SamplerOptions)> = self
+            .paths
+            .keys()
+            .filter(|(loaded, _)| loaded == path)
+            .cloned()
+            .collect();
+        if keys.is_empty() {
+            return Ok(false);
+        }
+        let bytes = std::fs::read(path)?;
+        let label = path.to_string_lossy();
+        for key in keys {
+            let texture = self.decode(device, queue, &bytes, &label, &key.1)?;
+            let filterable = is_filterable(device, &texture);
+            let bind_group = self.create_bind_group(device, &texture, filterable);
+            let entry = self.entries.get_mut(&self.paths[&key]).unwrap();
+            if let Some(handle) = entry.handle.upgrade() {
+                let size = texture.texture.size();
+                handle.width.store(size.width, Ordering::Relaxed);
+                handle.height.store(size.height, Ordering::Relaxed);
+            }
+            // Dropped rather than destroyed, since writes to it may still be queued.
+            entry.texture = texture;
+            entry.bind_group = bind_group;
+            entry.filterable = filterable;
+        }
+        Ok(true)
+    }
+
+    pub fn get(&self, handle: &TextureHandle) -> Option<&Texture> {
+        self.entries.get(&handle.id()).map(|entry| &entry.texture)
+    }
+
+    pub fn bind_group(&self, id: TextureId) -> Option<&wgpu::BindGroup> {
+        self.entries.get(&id).map(|entry| &entry.bind_group)
+    }
+
+    /// Whether the bind group of the texture `id` has `bind_group_layout`, rather than
+    /// `unfilterable_bind_group_layout`.
+    pub fn is_filterable(&self, id: TextureId) -> bool {
+        self.entries.get(&id).is_none_or(|entry| entry.filterable)
+    }
+
+    /// The number of textures held, including those whose last handle was dropped since
+    /// the last `free_unused`.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Frees the textures that no handle refers to anymore. The renderer calls it before
+    /// every frame.
+    pub fn free_unused(&mut self) {
+        self.entries.retain(|id, entry| {
+            let used = entry.handle.strong_count() > 0;
+            // Unused textures are dropped rather than destroyed, since writes to them may
+            // still be queued. wgpu frees them once the GPU is done with them.
+            if !used {
+                // The path may have been loaded again since the handle was dropped.
+                if let Some(path) = &entry.path {
+                    if self.paths.get(path) == Some(id) {
+                        self.paths.remove(path);
+                    }
+                }
+            }
+            used
+        });
+    }
+
+    fn create_bind_group(
+        &self,
+        device: &Device,
+        texture: &Texture,
+        filterable: bool,
+    ) -> wgpu::BindGroup {
+        let layout = if filterable {
+            &self.bind_group_layout
+        } else {
+            &self.unfilterable_bind_group_layout
+        };
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                },
+            ],
+            label: Some("texture_bind_group"),
+        })
+    }
+}
+
+fn is_filterable(device: &Device, texture: &Texture) -> bool {
+    texture
+        .texture
+        .format()
+        .sample_type(None, Some(device.features()))
+        == Some(wgpu::TextureSampleType::Float { filterable: true })
+}
+
+fn create_bind_group_layout(device: &Device, filterable: bool) -> wgpu::BindGroupLayout {
+    let sampler = if filterable {
+        wgpu::SamplerBindingType::Filtering
+    } else {
+        wgpu::SamplerBindingType::NonFiltering
+    };
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(sampler),
+                count: None,
+            },
+        ],
+        label: Some("texture_bind_group_layout"),
+    })
+}
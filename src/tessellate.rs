@@ -1,16 +1,33 @@
-use wgpu::{Device, Queue, SurfaceConfiguration};
-use wgpu::util::DeviceExt;
+use std::mem;
+use std::num::NonZeroU64;
+use std::ops::Range;
+
+use anyhow::Result;
+use bytemuck::cast_slice;
+use wgpu::{Device, SurfaceConfiguration};
+
+use crate::canvas::{Canvas, ScissorRect, Tessellate};
+use crate::material::{MaterialId, MaterialPipeline};
+use crate::reload;
+use crate::store::TextureId;
+use crate::texture::{Texture, TexturePipeline};
+use crate::view::{self, View};
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct TessellateVertex {
     pub color: [f32; 4],
     pub position: [f32; 3],
+    /// Position across a feathered stroke (-1 to 1) and how much of that is the fringe on
+    /// either side, 0 for strokes that are not feathered, see `Canvas::feather`.
+    pub edge: [f32; 2],
+    /// Position inside the bounds of the path, from the top left (0, 0) to the bottom right
+    /// (1, 1), for materials, see `MaterialPipeline::register`.
+    pub uv: [f32; 2],
 }
 
 impl TessellateVertex {
     pub fn desc() -> wgpu::VertexBufferLayout<'static> {
-        use std::mem;
         wgpu::VertexBufferLayout {
             array_stride: mem::size_of::<TessellateVertex>() as wgpu::BufferAddress,
             step_mode: wgpu::VertexStepMode::Vertex,
@@ -25,6 +42,16 @@ impl TessellateVertex {
                     shader_location: 1,
                     format: wgpu::VertexFormat::Float32x3,
                 },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 7]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 9]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
             ],
         }
     }
@@ -32,17 +59,117 @@ impl TessellateVertex {
 
 pub struct TessellatePipeline {
     pub render_pipeline: wgpu::RenderPipeline,
+    /// Increments the stencil value inside a clip path, see `Canvas::clips`.
+    pub clip_push_pipeline: wgpu::RenderPipeline,
+    /// Decrements the stencil value inside a clip path again once it is no longer used.
+    pub clip_pop_pipeline: wgpu::RenderPipeline,
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
     pub num_indices: u32,
     pub staging_belt: wgpu::util::StagingBelt,
+    /// Where the tessellates of the last uploaded canvas are, indexed like
+    /// `Canvas::tessellates` and `Canvas::clips`.
+    shape_slots: Vec<Option<Slot>>,
+    clip_slots: Vec<Option<Slot>>,
+    /// The parts of the vertex and index buffers handed out to slots.
+    vertex_ranges: RangeAllocator,
+    index_ranges: RangeAllocator,
+}
+
+/// Buffers are not compacted while they hold fewer indices than this.
+const MIN_COMPACT_INDICES: u32 = 1 << 16;
+
+/// The part of the vertex and index buffers set aside for one `Tessellate`, and the version
+/// of it that was written there.
+#[derive(Debug, Clone)]
+struct Slot {
+    version: u64,
+    vertices: Range<u32>,
+    indices: Range<u32>,
+}
+
+impl Slot {
+    fn range(&self, tessellate: &Tessellate) -> TessellateRange {
+        let start = self.indices.start;
+        TessellateRange {
+            indices: start..start + tessellate.indices.len() as u32,
+            instances: 0..0,
+        }
+    }
+}
+
+/// Hands out ranges of a buffer, reusing freed ones before growing it.
+#[derive(Debug, Default)]
+struct RangeAllocator {
+    /// The end of the last range handed out, the buffer is unused after it.
+    len: u32,
+    /// Ranges that were freed and can be handed out again.
+    free: Vec<Range<u32>>,
+}
+
+impl RangeAllocator {
+    /// Returns the first free range that is big enough, or a new one at the end.
+    fn allocate(&mut self, count: u32) -> Range<u32> {
+        if let Some(index) = self
+            .free
+            .iter()
+            .position(|range| range.len() as u32 >= count)
+        {
+            let start = self.free[index].start;
+            self.free[index].start += count;
+            if self.free[index].is_empty() {
+                self.free.remove(index);
+            }
+            return start..start + count;
+        }
+        let start = self.len;
+        self.len += count;
+        start..self.len
+    }
+
+    fn free(&mut self, range: Range<u32>) {
+        if !range.is_empty() {
+            self.free.push(range);
+        }
+    }
+
+    /// Merges neighbouring free ranges, and gives those at the end back.
+    fn merge_free(&mut self) {
+        self.free.sort_unstable_by_key(|range| range.start);
+        let mut merged: Vec<Range<u32>> = vec![];
+        for range in self.free.drain(..) {
+            match merged.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => merged.push(range),
+            }
+        }
+        if merged.last().is_some_and(|last| last.end == self.len) {
+            self.len = merged.pop().unwrap().start;
+        }
+        self.free = merged;
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+        self.free.clear();
+    }
+}
+
+/// Location of the indices of one uploaded `Tessellate` inside the shared index buffer. The
+/// indices are rebased onto the shared vertex buffer, so tessellates that follow each other
+/// in the buffer can be drawn with a single call. Shapes that were culled have an empty range.
+#[derive(Debug, Clone, Default)]
+pub struct TessellateRange {
+    pub indices: Range<u32>,
+    /// Shapes filled with a texture are drawn from the instance buffer of the
+    /// `TexturePipeline` instead, see `TexturePipeline::upload`.
+    pub instances: Range<u32>,
 }
 
 pub fn create_tessellate_pipeline(
     device: &Device,
-    queue: &Queue,
     config: &SurfaceConfiguration,
-    
+    sample_count: u32,
 ) -> TessellatePipeline {
 
     let tessellate_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
@@ -50,9 +177,54 @@ pub fn create_tessellate_pipeline(
         source: wgpu::ShaderSource::Wgsl(include_str!("shaders/tessellate_shader.wgsl").into()),
     });
 
+    let [render_pipeline, clip_push_pipeline, clip_pop_pipeline] =
+        create_pipelines(device, config, sample_count, &tessellate_shader);
+
+    let vertex_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Tessellate Vertex Buffer"),
+        usage: wgpu::BufferUsages::VERTEX
+            | wgpu::BufferUsages::COPY_DST
+            | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+        size: 1024, // grown in `TessellatePipeline::upload`
+    });
+    let index_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Tessellate Index Buffer"),
+        usage: wgpu::BufferUsages::INDEX
+            | wgpu::BufferUsages::COPY_DST
+            | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+        size: 1024, // grown in `TessellatePipeline::upload`
+    });
+
+    let staging_belt = wgpu::util::StagingBelt::new(1024);
+
+    TessellatePipeline {
+        render_pipeline,
+        clip_push_pipeline,
+        clip_pop_pipeline,
+        vertex_buffer,
+        index_buffer,
+        num_indices: 0,
+        staging_belt,
+        shape_slots: vec![],
+        clip_slots: vec![],
+        vertex_ranges: RangeAllocator::default(),
+        index_ranges: RangeAllocator::default(),
+    }
+}
+
+/// Creates the render, clip push and clip pop pipelines, which are all the pipelines using
+/// `shader`.
+fn create_pipelines(
+    device: &Device,
+    config: &SurfaceConfiguration,
+    sample_count: u32,
+    shader: &wgpu::ShaderModule,
+) -> [wgpu::RenderPipeline; 3] {
     let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
         label: Some("Tessellate Render Pipeline Layout"),
-        bind_group_layouts: &[],
+        bind_group_layouts: &[&view::create_bind_group_layout(device)],
         push_constant_ranges: &[],
     });
 
@@ -61,19 +233,24 @@ pub fn create_tessellate_pipeline(
         layout: Some(&render_pipeline_layout),
 
         vertex: wgpu::VertexState {
-            module: &tessellate_shader,
+            module: shader,
             entry_point: "vs_main",
             buffers: &[TessellateVertex::desc()],
         },
 
         fragment: Some(wgpu::FragmentState {
-            module: &tessellate_shader,
+            module: shader,
             entry_point: "fs_main",
             targets: &[Some(wgpu::ColorTargetState {
                 format: config.format,
+                // Layer targets hold premultiplied colors, see `layer_shader.wgsl`.
                 blend: Some(wgpu::BlendState {
-                    color: wgpu::BlendComponent::REPLACE,
-                    alpha: wgpu::BlendComponent::REPLACE,
+                    color: wgpu::BlendComponent {
+                        src_factor: wgpu::BlendFactor::SrcAlpha,
+                        dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                        operation: wgpu::BlendOperation::Add,
+                    },
+                    alpha: wgpu::BlendComponent::OVER,
                 }),
                 write_mask: wgpu::ColorWrites::ALL,
             })],
@@ -83,7 +260,8 @@ pub fn create_tessellate_pipeline(
             topology: wgpu::PrimitiveTopology::TriangleList,
             strip_index_format: None,
             front_face: wgpu::FrontFace::Ccw,
-            cull_mode: Some(wgpu::Face::Front),
+            // Transforms may mirror the shapes.
+            cull_mode: None,
             // Setting this to anything other than Fill requires Features::POLYGON_MODE_LINE
             // or Features::POLYGON_MODE_POINT
             polygon_mode: wgpu::PolygonMode::Fill,
@@ -94,14 +272,17 @@ pub fn create_tessellate_pipeline(
         },
 
         depth_stencil: Some(wgpu::DepthStencilState {
-            format: wgpu::TextureFormat::Depth32Float,
-            depth_write_enabled: true,
-            depth_compare: wgpu::CompareFunction::Less,
-            stencil: wgpu::StencilState::default(),
+            format: Texture::DEPTH_FORMAT,
+            // Shapes are painted in order, later ones on top, see `Canvas::paint_order`.
+            depth_write_enabled: false,
+            depth_compare: wgpu::CompareFunction::Always,
+            // Shapes are only drawn where the stencil value matches the depth of their clip
+            // stack, which is set as the stencil reference before each draw.
+            stencil: clip_stencil_state(wgpu::StencilOperation::Keep, 0x00),
             bias: wgpu::DepthBiasState::default(),
         }),
         multisample: wgpu::MultisampleState {
-            count: 1,
+            count: sample_count,
             mask: !0,
             alpha_to_coverage_enabled: false,
         },
@@ -110,26 +291,560 @@ pub fn create_tessellate_pipeline(
         multiview: None,
     });
 
-    let vertex_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-        label: Some("Tessellate Vertex Buffer"),
-        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-        mapped_at_creation: false,
-        size: 1024, // TODO: what size should be used here?
-    });
-    let index_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-        label: Some("Tessellate Index Buffer"),
-        usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
+    let clip_push_pipeline = create_clip_pipeline(
+        device,
+        &render_pipeline_layout,
+        shader,
+        config,
+        sample_count,
+        "Tessellate Clip Push Pipeline",
+        wgpu::StencilOperation::IncrementClamp,
+    );
+    let clip_pop_pipeline = create_clip_pipeline(
+        device,
+        &render_pipeline_layout,
+        shader,
+        config,
+        sample_count,
+        "Tessellate Clip Pop Pipeline",
+        wgpu::StencilOperation::DecrementClamp,
+    );
+
+    [render_pipeline, clip_push_pipeline, clip_pop_pipeline]
+}
+
+pub(crate) fn clip_stencil_state(
+    pass_op: wgpu::StencilOperation,
+    write_mask: u32,
+) -> wgpu::StencilState {
+    let face = wgpu::StencilFaceState {
+        compare: wgpu::CompareFunction::Equal,
+        fail_op: wgpu::StencilOperation::Keep,
+        depth_fail_op: wgpu::StencilOperation::Keep,
+        pass_op,
+    };
+    wgpu::StencilState {
+        front: face,
+        back: face,
+        read_mask: 0xff,
+        write_mask,
+    }
+}
+
+/// Creates a pipeline that only touches the stencil buffer. Clip paths are filled, so both
+/// faces are drawn and neither color nor depth is written.
+fn create_clip_pipeline(
+    device: &Device,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    config: &SurfaceConfiguration,
+    sample_count: u32,
+    label: &str,
+    pass_op: wgpu::StencilOperation,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_main",
+            buffers: &[TessellateVertex::desc()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format: config.format,
+                blend: None,
+                write_mask: wgpu::ColorWrites::empty(),
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: None,
+            polygon_mode: wgpu::PolygonMode::Fill,
+            unclipped_depth: false,
+            conservative: false,
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: Texture::DEPTH_FORMAT,
+            depth_write_enabled: false,
+            depth_compare: wgpu::CompareFunction::Always,
+            stencil: clip_stencil_state(pass_op, 0xff),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: sample_count,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+    })
+}
+
+impl TessellatePipeline {
+    /// Recreates the pipelines with `shader`, a new build of `tessellate_shader.wgsl`. The
+    /// current pipelines are kept when that fails.
+    pub fn set_shader(
+        &mut self,
+        device: &Device,
+        config: &SurfaceConfiguration,
+        sample_count: u32,
+        shader: &wgpu::ShaderModule,
+    ) -> Result<()> {
+        let pipelines = reload::capture_errors(device, || {
+            create_pipelines(device, config, sample_count, shader)
+        })?;
+        [
+            self.render_pipeline,
+            self.clip_push_pipeline,
+            self.clip_pop_pipeline,
+        ] = pipelines;
+        Ok(())
+    }
+
+    /// Makes sure the `visible` shapes and all clips of `canvas` are in the vertex and index
+    /// buffers and returns where each one is, shapes first, then clips. Tessellates are kept
+    /// between calls, only new or changed ones are written through the staging belt. Shapes
+    /// filled with a texture are left to the `TexturePipeline`.
+    pub fn upload(
+        &mut self,
+        device: &Device,
+        encoder: &mut wgpu::CommandEncoder,
+        canvas: &Canvas,
+        visible: &[usize],
+    ) -> Vec<TessellateRange> {
+        let clips: Vec<&Tessellate> = canvas.clips.iter().map(|clip| &clip.tessellate).collect();
+        let visible: Vec<usize> = visible
+            .iter()
+            .copied()
+            .filter(|&shape| canvas.tessellates[shape].texture.is_none())
+            .collect();
+        // The slots of shapes that are gone, removed or culled are freed for others. Culled
+        // shapes are written again once they are visible.
+        let mut uploaded = vec![false; canvas.tessellates.len()];
+        for &shape in &visible {
+            uploaded[shape] = true;
+        }
+        let mut freed: Vec<Slot> = vec![];
+        for (shape, slot) in self.shape_slots.iter_mut().enumerate() {
+            if !uploaded.get(shape).copied().unwrap_or(false) {
+                freed.extend(slot.take());
+            }
+        }
+        freed.extend(
+            self.clip_slots
+                .iter_mut()
+                .skip(clips.len())
+                .filter_map(Option::take),
+        );
+        for slot in freed {
+            self.free(slot);
+        }
+        self.vertex_ranges.merge_free();
+        self.index_ranges.merge_free();
+        self.shape_slots.resize(canvas.tessellates.len(), None);
+        self.clip_slots.resize(clips.len(), None);
+
+        // Freed slots too small to be reused leave holes behind. Once the holes make up
+        // most of the buffers, everything is written again from the start.
+        let needed: u32 = visible
+            .iter()
+            .map(|&shape| &canvas.tessellates[shape])
+            .chain(clips.iter().copied())
+            .map(|tessellate| tessellate.indices.len() as u32)
+            .sum();
+        if self.index_ranges.len > 2 * needed + MIN_COMPACT_INDICES {
+            self.shape_slots.iter_mut().for_each(|slot| *slot = None);
+            self.clip_slots.iter_mut().for_each(|slot| *slot = None);
+            self.vertex_ranges.clear();
+            self.index_ranges.clear();
+        }
+
+        let old_lens = (self.vertex_ranges.len, self.index_ranges.len);
+        let mut writes: Vec<(&Tessellate, Slot)> = vec![];
+        for &shape in &visible {
+            let tessellate = &canvas.tessellates[shape];
+            if let Some(slot) = self.allocate(self.shape_slots[shape].clone(), tessellate) {
+                self.shape_slots[shape] = Some(slot.clone());
+                writes.push((tessellate, slot));
+            }
+        }
+        for (clip, tessellate) in clips.iter().enumerate() {
+            if let Some(slot) = self.allocate(self.clip_slots[clip].clone(), tessellate) {
+                self.clip_slots[clip] = Some(slot.clone());
+                writes.push((tessellate, slot));
+            }
+        }
+
+        let vertex_size = mem::size_of::<TessellateVertex>() as u64;
+        grow_buffer(
+            device,
+            encoder,
+            &mut self.vertex_buffer,
+            self.vertex_ranges.len as u64 * vertex_size,
+            old_lens.0 as u64 * vertex_size,
+            "Tessellate Vertex Buffer",
+            wgpu::BufferUsages::VERTEX,
+        );
+        grow_buffer(
+            device,
+            encoder,
+            &mut self.index_buffer,
+            self.index_ranges.len as u64 * 4,
+            old_lens.1 as u64 * 4,
+            "Tessellate Index Buffer",
+            wgpu::BufferUsages::INDEX,
+        );
+        for (tessellate, slot) in writes {
+            let indices: Vec<u32> = tessellate
+                .indices
+                .iter()
+                .map(|index| index + slot.vertices.start)
+                .collect();
+            write_buffer(
+                device,
+                encoder,
+                &mut self.staging_belt,
+                &self.vertex_buffer,
+                slot.vertices.start as u64 * vertex_size,
+                cast_slice(&tessellate.vertices),
+            );
+            write_buffer(
+                device,
+                encoder,
+                &mut self.staging_belt,
+                &self.index_buffer,
+                slot.indices.start as u64 * 4,
+                cast_slice(&indices),
+            );
+        }
+        self.staging_belt.finish();
+        self.num_indices = self.index_ranges.len;
+
+        let mut ranges = vec![TessellateRange::default(); canvas.tessellates.len()];
+        for &shape in &visible {
+            let slot = self.shape_slots[shape].as_ref().unwrap();
+            ranges[shape] = slot.range(&canvas.tessellates[shape]);
+        }
+        for (slot, tessellate) in self.clip_slots.iter().zip(&clips) {
+            ranges.push(slot.as_ref().unwrap().range(tessellate));
+        }
+        ranges
+    }
+
+    /// Returns the slot `tessellate` has to be written to, or `None` when `slot` already
+    /// holds its current version. Slots are reused when the tessellate still fits, and
+    /// freed otherwise.
+    fn allocate(&mut self, slot: Option<Slot>, tessellate: &Tessellate) -> Option<Slot> {
+        let vertex_count = tessellate.vertices.len() as u32;
+        let index_count = tessellate.indices.len() as u32;
+        match slot {
+            Some(slot) if slot.version == tessellate.version => None,
+            Some(slot)
+                if slot.vertices.len() as u32 >= vertex_count
+                    && slot.indices.len() as u32 >= index_count =>
+            {
+                Some(Slot {
+                    version: tessellate.version,
+                    ..slot
+                })
+            }
+            slot => {
+                if let Some(slot) = slot {
+                    self.free(slot);
+                }
+                Some(Slot {
+                    version: tessellate.version,
+                    vertices: self.vertex_ranges.allocate(vertex_count),
+                    indices: self.index_ranges.allocate(index_count),
+                })
+            }
+        }
+    }
+
+    fn free(&mut self, slot: Slot) {
+        self.vertex_ranges.free(slot.vertices);
+        self.index_ranges.free(slot.indices);
+    }
+
+    /// Draws the tessellates of `canvas` listed in `shapes`, in as few calls as the clips and
+    /// scissors allow, and those filled with a texture through `texture_pipeline`. `ranges`
+    /// has to come from `upload` with the same canvas. Every clip pushed here is popped again,
+    /// so the stencil buffer is left as it was found.
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        texture_pipeline: &'a TexturePipeline,
+        material_pipeline: &'a MaterialPipeline,
+        canvas: &Canvas,
+        ranges: &[TessellateRange],
+        shapes: &[usize],
+        view: &'a View,
+    ) {
+        let (shape_ranges, clip_ranges) = ranges.split_at(canvas.tessellates.len());
+        let full = view.full();
+
+        // The clips whose stencil is currently written, outermost first.
+        let mut active: Vec<usize> = vec![];
+        // Shapes next to each other in the index or instance buffer with the same clips,
+        // scissor, texture and material are drawn together.
+        let mut batch = Batch {
+            range: 0..0,
+            texture: None,
+            colormap: None,
+            material: None,
+            scissor: full,
+        };
+        for &shape in shapes {
+            let tessellate = &canvas.tessellates[shape];
+            let (range, texture, colormap, material) = match &tessellate.texture {
+                Some(fill) => (
+                    &shape_ranges[shape].instances,
+                    Some(fill.texture.id()),
+                    fill.colormap.as_ref().map(|colormap| colormap.texture.id()),
+                    None,
+                ),
+                None => (
+                    &shape_ranges[shape].indices,
+                    None,
+                    None,
+                    tessellate.material,
+                ),
+            };
+            if range.is_empty() {
+                continue;
+            }
+            let chain = canvas.clip_chain(tessellate.clip);
+            let common = active
+                .iter()
+                .zip(&chain)
+                .take_while(|(a, b)| a == b)
+                .count();
+            let clips_changed = active.len() > common || chain.len() > common;
+            let scissor = tessellate
+                .scissor
+                .map_or(full, |scissor| view.scissor(&scissor));
+
+            if clips_changed
+                || scissor != batch.scissor
+                || texture != batch.texture
+                || colormap != batch.colormap
+                || material != batch.material
+                || range.start != batch.range.end
+            {
+                self.draw_batch(
+                    render_pass,
+                    texture_pipeline,
+                    material_pipeline,
+                    &mut batch,
+                    active.len(),
+                    view,
+                );
+            }
+            if clips_changed {
+                render_pass.set_scissor_rect(full.x, full.y, full.width, full.height);
+                self.pop_clips(render_pass, &mut active, common, clip_ranges, view);
+                for &clip in &chain[common..] {
+                    let range = &clip_ranges[clip];
+                    self.set_buffers(render_pass, view);
+                    render_pass.set_pipeline(&self.clip_push_pipeline);
+                    render_pass.set_stencil_reference(active.len() as u32);
+                    render_pass.draw_indexed(range.indices.clone(), 0, 0..1);
+                    active.push(clip);
+                }
+            }
+
+            if scissor.width == 0 || scissor.height == 0 {
+                continue;
+            }
+            if batch.range.is_empty() {
+                batch = Batch {
+                    range: range.clone(),
+                    texture,
+                    colormap,
+                    material,
+                    scissor,
+                };
+            } else {
+                batch.range.end = range.end;
+            }
+        }
+        self.draw_batch(
+            render_pass,
+            texture_pipeline,
+            material_pipeline,
+            &mut batch,
+            active.len(),
+            view,
+        );
+        render_pass.set_scissor_rect(full.x, full.y, full.width, full.height);
+        self.pop_clips(render_pass, &mut active, 0, clip_ranges, view);
+    }
+
+    /// Draws the shapes collected in `batch` inside `clip_depth` clips and empties it.
+    fn draw_batch<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        texture_pipeline: &'a TexturePipeline,
+        material_pipeline: &'a MaterialPipeline,
+        batch: &mut Batch,
+        clip_depth: usize,
+        view: &'a View,
+    ) {
+        if batch.range.is_empty() {
+            return;
+        }
+        let scissor = batch.scissor;
+        render_pass.set_scissor_rect(scissor.x, scissor.y, scissor.width, scissor.height);
+        match batch.texture {
+            Some(texture) => {
+                texture_pipeline.draw_instances(
+                    render_pass,
+                    texture,
+                    batch.colormap,
+                    batch.range.clone(),
+                    clip_depth,
+                    view,
+                );
+            }
+            None => {
+                self.set_buffers(render_pass, view);
+                let bound = batch
+                    .material
+                    .is_some_and(|material| material_pipeline.bind(render_pass, material, view));
+                if !bound {
+                    render_pass.set_pipeline(&self.render_pipeline);
+                }
+                render_pass.set_stencil_reference(clip_depth as u32);
+                render_pass.draw_indexed(batch.range.clone(), 0, 0..1);
+            }
+        }
+        batch.range = 0..0;
+    }
+
+    fn pop_clips<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        active: &mut Vec<usize>,
+        len: usize,
+        clip_ranges: &[TessellateRange],
+        view: &'a View,
+    ) {
+        while active.len() > len {
+            let clip = active.pop().unwrap();
+            let range = &clip_ranges[clip];
+            self.set_buffers(render_pass, view);
+            render_pass.set_pipeline(&self.clip_pop_pipeline);
+            render_pass.set_stencil_reference(active.len() as u32 + 1);
+            render_pass.draw_indexed(range.indices.clone(), 0, 0..1);
+        }
+    }
+
+    /// Binds the vertex and index buffers, which textured batches replace with their own,
+    /// and `view` as group 0.
+    fn set_buffers<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, view: &'a View) {
+        render_pass.set_bind_group(0, &view.bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+    }
+}
+
+/// Consecutive shapes drawn with one `draw_indexed` call: a range of indices, drawn with
+/// `material` if there is one, or of instances when they are filled with `texture`, looked
+/// up in `colormap`.
+struct Batch {
+    range: Range<u32>,
+    texture: Option<TextureId>,
+    colormap: Option<TextureId>,
+    material: Option<MaterialId>,
+    scissor: ScissorRect,
+}
+
+/// Replaces `buffer` with a bigger one when it is smaller than `size`, keeping the first
+/// `used` bytes.
+fn grow_buffer(
+    device: &Device,
+    encoder: &mut wgpu::CommandEncoder,
+    buffer: &mut wgpu::Buffer,
+    size: u64,
+    used: u64,
+    label: &str,
+    usage: wgpu::BufferUsages,
+) {
+    if buffer.size() >= size {
+        return;
+    }
+    let new_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some(label),
+        usage: usage | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
         mapped_at_creation: false,
-        size: 1024, // TODO: what size should be used here?
+        size: size.next_power_of_two(),
     });
+    if used > 0 {
+        encoder.copy_buffer_to_buffer(buffer, 0, &new_buffer, 0, used);
+    }
+    *buffer = new_buffer;
+}
 
-    let staging_belt = wgpu::util::StagingBelt::new(1024);
+/// Writes `data` to `buffer` at `offset`, padded with zeros to `wgpu::COPY_BUFFER_ALIGNMENT`.
+fn write_buffer(
+    device: &Device,
+    encoder: &mut wgpu::CommandEncoder,
+    staging_belt: &mut wgpu::util::StagingBelt,
+    buffer: &wgpu::Buffer,
+    offset: u64,
+    data: &[u8],
+) {
+    let Some(size) = NonZeroU64::new(wgpu::util::align_to(
+        data.len() as u64,
+        wgpu::COPY_BUFFER_ALIGNMENT,
+    )) else {
+        return;
+    };
+    let mut view = staging_belt.write_buffer(encoder, buffer, offset, size, device);
+    view[..data.len()].copy_from_slice(data);
+    view[data.len()..].fill(0);
+}
 
-    TessellatePipeline {
-        render_pipeline,
-        vertex_buffer,
-        index_buffer,
-        num_indices: 0,
-        staging_belt,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_allocator_reuses_freed_ranges() {
+        let mut ranges = RangeAllocator::default();
+        let a = ranges.allocate(10);
+        let b = ranges.allocate(20);
+        let c = ranges.allocate(5);
+        assert_eq!((a.clone(), b.clone(), c.clone()), (0..10, 10..30, 30..35));
+
+        ranges.free(b);
+        assert_eq!(ranges.allocate(8), 10..18);
+        // Too big for the rest of the hole, so the buffer grows.
+        assert_eq!(ranges.allocate(16), 35..51);
+        assert_eq!(ranges.allocate(12), 18..30);
+    }
+
+    #[test]
+    fn range_allocator_merges_and_shrinks() {
+        let mut ranges = RangeAllocator::default();
+        let a = ranges.allocate(10);
+        let b = ranges.allocate(10);
+        let c = ranges.allocate(10);
+        ranges.free(a);
+        ranges.free(b);
+        ranges.merge_free();
+        assert_eq!(ranges.free, vec![0..20]);
+        assert_eq!(ranges.allocate(15), 0..15);
+
+        // The free ranges at the end are given back.
+        ranges.free(c);
+        ranges.merge_free();
+        assert_eq!(ranges.len, 15);
+        assert!(ranges.free.is_empty());
     }
 }
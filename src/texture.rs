@@ -1,10 +1,18 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
 use anyhow::*;
-use cgmath::Rotation3;
-use cgmath::{InnerSpace, Zero};
 use image::GenericImageView;
+use lyon::math::{Box2D, Transform};
 use wgpu::{Device, Queue, SurfaceConfiguration};
 use wgpu::util::DeviceExt;
 
+use crate::canvas::Canvas;
+use crate::reload;
+use crate::store::{TextureHandle, TextureId, TextureStore};
+use crate::tessellate::{self, TessellateRange};
+use crate::view::{self, View};
+
 pub struct Texture {
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
@@ -12,11 +20,13 @@ pub struct Texture {
 }
 
 impl Texture {
-    pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+    /// Shared by all pipelines; the stencil aspect holds the clip paths of the canvas.
+    pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth24PlusStencil8;
 
     pub fn create_depth_texture(
         device: &wgpu::Device,
         config: &wgpu::SurfaceConfiguration,
+        sample_count: u32,
         label: &str,
     ) -> Self {
         let size = wgpu::Extent3d {
@@ -24,14 +34,21 @@ impl Texture {
             height: config.height,
             depth_or_array_layers: 1,
         };
+        // Multisampled depth is never sampled. It also has to match the usage of the MSAA
+        // color target, or GL backends end up with an incomplete framebuffer.
+        let usage = if sample_count > 1 {
+            wgpu::TextureUsages::RENDER_ATTACHMENT
+        } else {
+            wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING
+        };
         let desc = wgpu::TextureDescriptor {
             label: Some(label),
             size,
             mip_level_count: 1,
-            sample_count: 1,
+            sample_count,
             dimension: wgpu::TextureDimension::D2,
             format: Self::DEPTH_FORMAT,
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            usage,
             view_formats: &[Self::DEPTH_FORMAT],
         };
         let texture = device.create_texture(&desc);
@@ -99,22 +116,117 @@ impl Texture {
         }
     }
 
+    /// A multisampled color target with the surface format. Render passes draw into it and
+    /// resolve it into the single sampled surface or layer texture.
+    pub fn create_multisampled_target(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        sample_count: u32,
+        label: &str,
+    ) -> Self {
+        let size = wgpu::Extent3d {
+            width: config.width,
+            height: config.height,
+            depth_or_array_layers: 1,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size,
+            mip_level_count: 1,
+            sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format: config.format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor::default());
+
+        Self {
+            texture,
+            view,
+            sampler,
+        }
+    }
+
+    /// A texture with the surface format that can be rendered into and sampled afterwards,
+    /// used for the intermediate targets of canvas layers.
+    pub fn create_render_target(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        label: &str,
+    ) -> Self {
+        let size = wgpu::Extent3d {
+            width: config.width,
+            height: config.height,
+            depth_or_array_layers: 1,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: config.format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        Self {
+            texture,
+            view,
+            sampler,
+        }
+    }
+
     pub fn from_bytes(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         bytes: &[u8],
         label: &str,
+        sampler: &SamplerOptions,
     ) -> Result<Self> {
         let img = image::load_from_memory(bytes)?;
-        Self::from_image(device, queue, &img, Some(label))
+        Self::from_image(device, queue, &img, Some(label), sampler)
     }
 
+    /// Uploads `img`, and with `SamplerOptions::mipmaps` the mip levels generated from it.
+    /// Images with 16 or 32 bits per channel keep their precision as data textures, see
+    /// `from_data`, whose values are not decoded from sRGB. The mipmap pipeline is created for this texture only, `from_image_with` reuses one.
     pub fn from_image(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         img: &image::DynamicImage,
         label: Option<&str>,
+        sampler: &SamplerOptions,
     ) -> Result<Self> {
+        let mut mipmaps = MipmapGenerator::default();
+        Self::from_image_with(device, queue, img, label, sampler, &mut mipmaps)
+    }
+
+    /// Like `from_image`, generating the mip levels with `mipmaps`.
+    pub fn from_image_with(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        img: &image::DynamicImage,
+        label: Option<&str>,
+        sampler: &SamplerOptions,
+        mipmaps: &mut MipmapGenerator,
+    ) -> Result<Self> {
+        if let Some((format, data)) = data_texels(img) {
+            let (width, height) = img.dimensions();
+            return Self::from_data(device, queue, format, width, height, &data, label, sampler);
+        }
         let rgba = img.to_rgba8();
         let dimensions = img.dimensions();
 
@@ -123,15 +235,24 @@ impl Texture {
             height: dimensions.1,
             depth_or_array_layers: 1,
         };
+        let mip_level_count = match sampler.mipmaps {
+            Some(_) => size.max_mips(wgpu::TextureDimension::D2),
+            None => 1,
+        };
         let format = wgpu::TextureFormat::Rgba8UnormSrgb;
+        let mut usage = wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST;
+        if mip_level_count > 1 {
+            // The smaller levels are rendered from the larger ones.
+            usage |= wgpu::TextureUsages::RENDER_ATTACHMENT;
+        }
         let texture = device.create_texture(&wgpu::TextureDescriptor {
             label,
             size,
-            mip_level_count: 1,
+            mip_level_count,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
             format,
-            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            usage,
             view_formats: &[],
         });
 
@@ -150,17 +271,12 @@ impl Texture {
             },
             size,
         );
+        if mip_level_count > 1 {
+            mipmaps.generate(device, queue, &texture);
+        }
 
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            address_mode_u: wgpu::AddressMode::ClampToEdge,
-            address_mode_v: wgpu::AddressMode::ClampToEdge,
-            address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Linear,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
-            ..Default::default()
-        });
+        let sampler = sampler.create_sampler(device);
 
         Ok(Self {
             texture,
@@ -168,6 +284,471 @@ impl Texture {
             sampler,
         })
     }
+
+    /// Uploads a 2D array of `width` by `height` values, row by row from the top, as a
+    /// single channel 32 bit float texture, to be drawn with `Canvas::draw_heatmap`.
+    pub fn from_scalars(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        width: u32,
+        height: u32,
+        values: &[f32],
+        label: Option<&str>,
+    ) -> Result<Self> {
+        let sampler = SamplerOptions {
+            mag_filter: wgpu::FilterMode::Nearest,
+            ..SamplerOptions::default()
+        };
+        let data = bytemuck::cast_slice(values);
+        Self::from_data(
+            device,
+            queue,
+            DataFormat::R32F,
+            width,
+            height,
+            data,
+            label,
+            &sampler,
+        )
+    }
+
+    /// Uploads `width` by `height` texels of raw data laid out as `format` describes, to be
+    /// drawn with a `ValueMapping`. Formats that cannot be filtered on `device` are sampled
+    /// with nearest filters, and data textures never have mipmaps.
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_data(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        format: DataFormat,
+        width: u32,
+        height: u32,
+        data: &[u8],
+        label: Option<&str>,
+        sampler: &SamplerOptions,
+    ) -> Result<Self> {
+        if width == 0 || height == 0 {
+            bail!("cannot upload an empty {width}x{height} data texture");
+        }
+        let expected = u64::from(width)
+            .checked_mul(u64::from(height))
+            .and_then(|texels| texels.checked_mul(u64::from(format.texel_size())))
+            .and_then(|bytes| usize::try_from(bytes).ok())
+            .with_context(|| format!("{format:?} data of {width}x{height} texels is too large"))?;
+        if data.len() != expected {
+            bail!(
+                "{:?} data of {}x{} texels needs {} bytes, got {}",
+                format,
+                width,
+                height,
+                expected,
+                data.len()
+            );
+        }
+        let (texture_format, data) = format.upload(device.features(), data);
+        let texel_size = texture_format.block_copy_size(None).unwrap();
+
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label,
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: texture_format,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            &data,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(texel_size * width),
+                rows_per_image: Some(height),
+            },
+            size,
+        );
+
+        let filterable = texture_format.sample_type(None, Some(device.features()))
+            == Some(wgpu::TextureSampleType::Float { filterable: true });
+        let sampler = if filterable {
+            SamplerOptions {
+                mipmaps: None,
+                ..*sampler
+            }
+        } else {
+            SamplerOptions {
+                mipmaps: None,
+                ..SamplerOptions::pixel_art()
+            }
+            .with_address_mode(sampler.address_mode_u)
+        };
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        Ok(Self {
+            texture,
+            view,
+            sampler: sampler.create_sampler(device),
+        })
+    }
+}
+
+/// The layout of the raw data passed to `Texture::from_data`, one texel after the other in
+/// rows from the top, little endian. Unsigned integers are normalized to 0 to 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DataFormat {
+    /// One `u8` per texel.
+    R8,
+    /// Two `u8` per texel.
+    Rg8,
+    /// One `u16` per texel.
+    R16,
+    /// Four half precision floats per texel.
+    Rgba16F,
+    /// One `f32` per texel.
+    R32F,
+    /// Two `f32` per texel.
+    Rg32F,
+    /// Four `f32` per texel.
+    Rgba32F,
+}
+
+impl DataFormat {
+    /// The size of one texel of the data in bytes.
+    pub fn texel_size(self) -> u32 {
+        match self {
+            DataFormat::R8 => 1,
+            DataFormat::Rg8 | DataFormat::R16 => 2,
+            DataFormat::R32F => 4,
+            DataFormat::Rgba16F | DataFormat::Rg32F => 8,
+            DataFormat::Rgba32F => 16,
+        }
+    }
+
+    /// Returns the texture format to upload `data` with, and the data in that format.
+    /// Without `Features::TEXTURE_FORMAT_16BIT_NORM`, 16 bit data is stored as 32 bit
+    /// floats, which hold every value exactly.
+    fn upload(self, features: wgpu::Features, data: &[u8]) -> (wgpu::TextureFormat, Vec<u8>) {
+        let format = match self {
+            DataFormat::R8 => wgpu::TextureFormat::R8Unorm,
+            DataFormat::Rg8 => wgpu::TextureFormat::Rg8Unorm,
+            DataFormat::R16 if features.contains(wgpu::Features::TEXTURE_FORMAT_16BIT_NORM) => {
+                wgpu::TextureFormat::R16Unorm
+            }
+            DataFormat::R16 => {
+                let floats: Vec<f32> = data
+                    .chunks_exact(2)
+                    .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 65535.0)
+                    .collect();
+                return (
+                    wgpu::TextureFormat::R32Float,
+                    bytemuck::cast_slice(&floats).to_vec(),
+                );
+            }
+            DataFormat::Rgba16F => wgpu::TextureFormat::Rgba16Float,
+            DataFormat::R32F => wgpu::TextureFormat::R32Float,
+            DataFormat::Rg32F => wgpu::TextureFormat::Rg32Float,
+            DataFormat::Rgba32F => wgpu::TextureFormat::Rgba32Float,
+        };
+        (format, data.to_vec())
+    }
+}
+
+/// The texels of images with more than 8 bits per channel, laid out for
+/// `Texture::from_data`, or `None` for other images. Channels missing from the image are
+/// filled in, so every image is shown the way it looks.
+fn data_texels(img: &image::DynamicImage) -> Option<(DataFormat, Vec<u8>)> {
+    use image::DynamicImage;
+    let normalize = |value: u16| value as f32 / 65535.0;
+    let (format, values): (DataFormat, Vec<f32>) = match img {
+        DynamicImage::ImageLuma16(image) => {
+            let data = image.as_raw().iter().flat_map(|value| value.to_le_bytes());
+            return Some((DataFormat::R16, data.collect()));
+        }
+        DynamicImage::ImageLumaA16(image) => {
+            let texels = image.pixels().flat_map(|pixel| {
+                let [luma, alpha] = pixel.0.map(normalize);
+                [luma, luma, luma, alpha]
+            });
+            (DataFormat::Rgba32F, texels.collect())
+        }
+        DynamicImage::ImageRgb16(image) => {
+            let texels = image.pixels().flat_map(|pixel| {
+                let [r, g, b] = pixel.0.map(normalize);
+                [r, g, b, 1.0]
+            });
+            (DataFormat::Rgba32F, texels.collect())
+        }
+        DynamicImage::ImageRgba16(image) => (
+            DataFormat::Rgba32F,
+            image.as_raw().iter().copied().map(normalize).collect(),
+        ),
+        DynamicImage::ImageRgb32F(image) => {
+            let texels = image.pixels().flat_map(|pixel| {
+                let [r, g, b] = pixel.0;
+                [r, g, b, 1.0]
+            });
+            (DataFormat::Rgba32F, texels.collect())
+        }
+        DynamicImage::ImageRgba32F(image) => (DataFormat::Rgba32F, image.as_raw().clone()),
+        _ => return None,
+    };
+    let data = values
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect();
+    Some((format, data))
+}
+
+/// Turns the values of a data texture into displayed brightness, from black at `min` to
+/// white at `max`, so data is shown at its full precision instead of 8 bits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValueMapping {
+    pub min: f32,
+    pub max: f32,
+    /// Shows the first channel in gray, for single channel data. Otherwise every channel
+    /// but alpha is mapped on its own.
+    pub grayscale: bool,
+}
+
+impl ValueMapping {
+    /// Shows the first channel in gray.
+    pub fn gray(min: f32, max: f32) -> ValueMapping {
+        ValueMapping {
+            min,
+            max,
+            grayscale: true,
+        }
+    }
+
+    /// Maps the red, green and blue channels each.
+    pub fn color(min: f32, max: f32) -> ValueMapping {
+        ValueMapping {
+            min,
+            max,
+            grayscale: false,
+        }
+    }
+
+    /// The window and level of medical imaging: a gray ramp `width` values wide centered
+    /// on `level`.
+    pub fn window_level(width: f32, level: f32) -> ValueMapping {
+        ValueMapping::gray(level - width / 2.0, level + width / 2.0)
+    }
+
+    /// The mapping as passed to the texture shader: min, max and the mode, which is 0
+    /// without a mapping, 1 for color, 2 for gray and 3 for a `colormap` lookup.
+    fn to_raw(mapping: Option<ValueMapping>, colormap: bool) -> [f32; 4] {
+        match mapping {
+            None => [0.0; 4],
+            Some(mapping) => {
+                let mode = if colormap {
+                    3.0
+                } else if mapping.grayscale {
+                    2.0
+                } else {
+                    1.0
+                };
+                [mapping.min, mapping.max, mode, 0.0]
+            }
+        }
+    }
+}
+
+// ============================================================================
+// Samplers
+// ============================================================================
+
+/// How a texture loaded with `Texture::from_image` is filtered and repeated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SamplerOptions {
+    /// Used when the texture is drawn larger than its size.
+    pub mag_filter: wgpu::FilterMode,
+    /// Used when the texture is drawn smaller than its size.
+    pub min_filter: wgpu::FilterMode,
+    /// Generates mipmaps when the texture is uploaded, blended with this filter between
+    /// levels. Without them downscaled textures shimmer.
+    pub mipmaps: Option<wgpu::FilterMode>,
+    /// Repeat, MirrorRepeat or ClampToEdge, for texture coordinates outside of 0 to 1.
+    pub address_mode_u: wgpu::AddressMode,
+    pub address_mode_v: wgpu::AddressMode,
+    /// The maximum anisotropy, from 1 to 16. It only applies when all filters are linear.
+    pub anisotropy: u16,
+}
+
+impl SamplerOptions {
+    /// Sharp pixels at any scale, for pixel art.
+    pub fn pixel_art() -> SamplerOptions {
+        SamplerOptions {
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmaps: None,
+            ..SamplerOptions::default()
+        }
+    }
+
+    /// Smooth at any scale and angle, for photos.
+    pub fn smooth() -> SamplerOptions {
+        SamplerOptions {
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmaps: Some(wgpu::FilterMode::Linear),
+            anisotropy: 16,
+            ..SamplerOptions::default()
+        }
+    }
+
+    /// The same options with `mode` in both directions.
+    pub fn with_address_mode(self, mode: wgpu::AddressMode) -> SamplerOptions {
+        SamplerOptions {
+            address_mode_u: mode,
+            address_mode_v: mode,
+            ..self
+        }
+    }
+
+    pub fn create_sampler(&self, device: &wgpu::Device) -> wgpu::Sampler {
+        let linear = self.mag_filter == wgpu::FilterMode::Linear
+            && self.min_filter == wgpu::FilterMode::Linear
+            && self.mipmaps == Some(wgpu::FilterMode::Linear);
+        device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: self.address_mode_u,
+            address_mode_v: self.address_mode_v,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: self.mag_filter,
+            min_filter: self.min_filter,
+            mipmap_filter: self.mipmaps.unwrap_or(wgpu::FilterMode::Nearest),
+            // wgpu rejects anisotropic samplers with nearest filters.
+            anisotropy_clamp: if linear {
+                self.anisotropy.clamp(1, 16)
+            } else {
+                1
+            },
+            ..Default::default()
+        })
+    }
+}
+
+impl Default for SamplerOptions {
+    /// Linear magnification, nearest minification, no mipmaps and clamped coordinates.
+    fn default() -> SamplerOptions {
+        SamplerOptions {
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmaps: None,
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            anisotropy: 1,
+        }
+    }
+}
+
+/// Renders the mip levels of textures, keeping a pipeline for every format it has seen.
+#[derive(Default)]
+pub struct MipmapGenerator {
+    shader: Option<wgpu::ShaderModule>,
+    sampler: Option<wgpu::Sampler>,
+    pipelines: HashMap<wgpu::TextureFormat, wgpu::RenderPipeline>,
+}
+
+impl MipmapGenerator {
+    /// Renders every mip level of `texture` after the first from the level before it.
+    pub fn generate(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        texture: &wgpu::Texture,
+    ) {
+        let shader = self.shader.get_or_insert_with(|| {
+            device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some("Mipmap Shader"),
+                source: wgpu::ShaderSource::Wgsl(include_str!("shaders/mipmap_shader.wgsl").into()),
+            })
+        });
+        let pipeline = self.pipelines.entry(texture.format()).or_insert_with(|| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("Mipmap Pipeline"),
+                layout: None,
+                vertex: wgpu::VertexState {
+                    module: shader,
+                    entry_point: "vs_main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: shader,
+                    entry_point: "fs_main",
+                    targets: &[Some(texture.format().into())],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            })
+        });
+        let sampler = self.sampler.get_or_insert_with(|| {
+            device.create_sampler(&wgpu::SamplerDescriptor {
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                ..Default::default()
+            })
+        });
+
+        let views: Vec<wgpu::TextureView> = (0..texture.mip_level_count())
+            .map(|level| {
+                texture.create_view(&wgpu::TextureViewDescriptor {
+                    base_mip_level: level,
+                    mip_level_count: Some(1),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Mipmap Encoder"),
+        });
+        for pair in views.windows(2) {
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &pipeline.get_bind_group_layout(0),
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&pair[0]),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(sampler),
+                    },
+                ],
+                label: Some("mipmap_bind_group"),
+            });
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Mipmap Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &pair[1],
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: None,
+            });
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(0, &bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+    }
 }
 
 // ============================================================================
@@ -203,30 +784,28 @@ impl TextureVertex {
     }
 }
 
+/// The unit square every instance is drawn from. Texture coordinates point down, so the
+/// top row of the image ends up at the top of the square.
 pub const TEXTURE_VERTICES: &[TextureVertex] = &[
     TextureVertex {
-        position: [-0.0868241, 0.49240386, 0.0],
-        tex_coords: [0.4131759, 0.00759614],
-    }, // A
-    TextureVertex {
-        position: [-0.49513406, 0.06958647, 0.0],
-        tex_coords: [0.0048659444, 0.43041354],
-    }, // B
+        position: [0.0, 0.0, 0.1],
+        tex_coords: [0.0, 1.0],
+    },
     TextureVertex {
-        position: [-0.21918549, -0.44939706, 0.0],
-        tex_coords: [0.28081453, 0.949397],
-    }, // C
+        position: [1.0, 0.0, 0.1],
+        tex_coords: [1.0, 1.0],
+    },
     TextureVertex {
-        position: [0.35966998, -0.3473291, 0.0],
-        tex_coords: [0.85967, 0.84732914],
-    }, // D
+        position: [1.0, 1.0, 0.1],
+        tex_coords: [1.0, 0.0],
+    },
     TextureVertex {
-        position: [0.44147372, 0.2347359, 0.0],
-        tex_coords: [0.9414737, 0.2652641],
-    }, // E
+        position: [0.0, 1.0, 0.1],
+        tex_coords: [0.0, 0.0],
+    },
 ];
 
-pub const TEXTURE_INDICES: &[u16] = &[0, 1, 4, 1, 2, 4, 2, 3, 4, /* padding */ 0];
+pub const TEXTURE_INDICES: &[u16] = &[0, 1, 2, 0, 2, 3];
 
 #[rustfmt::skip]
 pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
@@ -236,28 +815,41 @@ pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
     0.0, 0.0, 0.0, 1.0,
 );
 
-const NUM_INSTANCES_PER_ROW: u32 = 10;
-const INSTANCE_DISPLACEMENT: cgmath::Vector3<f32> = cgmath::Vector3::new(
-    NUM_INSTANCES_PER_ROW as f32 * 0.5,
-    0.0,
-    NUM_INSTANCES_PER_ROW as f32 * 0.5,
-);
-
 // ============================================================================
 // Instances
 // ============================================================================
 
+/// One textured quad.
+#[derive(Debug, Clone, Copy)]
 pub struct TextureInstance {
-    position: cgmath::Vector3<f32>,
-    rotation: cgmath::Quaternion<f32>,
+    /// Places the unit square in canvas coordinates.
+    pub transform: Transform,
+    /// The part of the texture drawn, in texture coordinates from 0 to 1 with y down.
+    pub uv: Box2D,
+    /// Multiplied with the texture color.
+    pub color: [f32; 4],
+    /// Applied to the texture color first, for data textures.
+    pub mapping: Option<ValueMapping>,
+    /// Set when the mapped first channel is looked up in the colormap the instance is
+    /// drawn with: the color drawn for NaN values.
+    pub nan_color: Option<[f32; 4]>,
 }
 
 impl TextureInstance {
     pub fn to_raw(&self) -> TextureInstanceRaw {
+        let t = &self.transform;
+        let size = self.uv.size();
         TextureInstanceRaw {
-            model: (cgmath::Matrix4::from_translation(self.position)
-                * cgmath::Matrix4::from(self.rotation))
-            .into(),
+            model: [
+                [t.m11, t.m12, 0.0, 0.0],
+                [t.m21, t.m22, 0.0, 0.0],
+                [0.0, 0.0, 1.0, 0.0],
+                [t.m31, t.m32, 0.0, 1.0],
+            ],
+            uv: [self.uv.min.x, self.uv.min.y, size.width, size.height],
+            color: self.color,
+            mapping: ValueMapping::to_raw(self.mapping, self.nan_color.is_some()),
+            nan_color: self.nan_color.unwrap_or_default(),
         }
     }
 }
@@ -266,6 +858,10 @@ impl TextureInstance {
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct TextureInstanceRaw {
     model: [[f32; 4]; 4],
+    uv: [f32; 4],
+    color: [f32; 4],
+    mapping: [f32; 4],
+    nan_color: [f32; 4],
 }
 
 impl TextureInstanceRaw {
@@ -280,8 +876,8 @@ impl TextureInstanceRaw {
             attributes: &[
                 wgpu::VertexAttribute {
                     offset: 0,
-                    // While our vertex shader only uses locations 0, and 1 now, in later tutorials we'll
-                    // be using 2, 3, and 4, for Vertex. We'll start at slot 5 not conflict with them later
+                    // The vertex uses locations 0 and 1, instances start at 5 to leave room
+                    // for more vertex attributes.
                     shader_location: 5,
                     format: wgpu::VertexFormat::Float32x4,
                 },
@@ -302,104 +898,59 @@ impl TextureInstanceRaw {
                     shader_location: 8,
                     format: wgpu::VertexFormat::Float32x4,
                 },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
+                    shader_location: 9,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 20]>() as wgpu::BufferAddress,
+                    shader_location: 10,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 24]>() as wgpu::BufferAddress,
+                    shader_location: 11,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 28]>() as wgpu::BufferAddress,
+                    shader_location: 12,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
             ],
         }
     }
 }
 
-pub fn static_instances() -> Vec<TextureInstance> {
-    (0..NUM_INSTANCES_PER_ROW)
-        .flat_map(|z| {
-            (0..NUM_INSTANCES_PER_ROW).map(move |x| {
-                let position = cgmath::Vector3 {
-                    x: x as f32,
-                    y: 0.0,
-                    z: z as f32,
-                } - INSTANCE_DISPLACEMENT;
-
-                let rotation = if position.is_zero() {
-                    // this is needed so an object at (0, 0, 0) won't get scaled to zero
-                    // as Quaternions can effect scale if they're not created correctly
-                    cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_z(), cgmath::Deg(0.0))
-                } else {
-                    cgmath::Quaternion::from_axis_angle(position.normalize(), cgmath::Deg(45.0))
-                };
-
-                TextureInstance { position, rotation }
-            })
-        })
-        .collect::<Vec<_>>()
-}
-
 pub struct TexturePipeline {
     pub render_pipeline: wgpu::RenderPipeline,
+    /// Draws textures whose format cannot be filtered, see `TextureStore::is_filterable`.
+    pub unfilterable_pipeline: wgpu::RenderPipeline,
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
     pub num_indices: u32,
-    pub diffuse_texture: Texture,
-    pub diffuse_bind_group: wgpu::BindGroup,
-    pub instances: Vec<TextureInstance>,
+    /// Holds the instances of the textured shapes of the last uploaded canvas.
     pub instance_buffer: wgpu::Buffer,
-    pub depth_texture: Texture,
+    pub textures: TextureStore,
+    /// Bound as the colormap of instances drawn without one, which never sample it.
+    no_colormap: TextureHandle,
 }
 
 pub fn create_texture_pipeline(
     device: &Device,
-    queue: &Queue,
     config: &SurfaceConfiguration,
+    sample_count: u32,
 ) -> TexturePipeline {
-    let diffuse_bytes = include_bytes!("floor.png");
-    let diffuse_texture =
-        Texture::from_bytes(&device, &queue, diffuse_bytes, "happy-tree.png").unwrap();
-
-    let texture_bind_group_layout =
-        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            entries: &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Texture {
-                        multisampled: false,
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                    count: None,
-                },
-            ],
-            label: Some("texture_bind_group_layout"),
-        });
-
-    let diffuse_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-        layout: &texture_bind_group_layout,
-        entries: &[
-            wgpu::BindGroupEntry {
-                binding: 0,
-                resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-            },
-            wgpu::BindGroupEntry {
-                binding: 1,
-                resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-            },
-        ],
-        label: Some("diffuse_bind_group"),
-    });
+    let mut textures = TextureStore::new(device);
+    let no_colormap = create_placeholder(device);
+    let no_colormap = textures.insert(device, no_colormap);
 
-    let instances = static_instances();
-
-    let instance_data = instances
-        .iter()
-        .map(TextureInstance::to_raw)
-        .collect::<Vec<_>>();
-    let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+    let instance_buffer = device.create_buffer(&wgpu::BufferDescriptor {
         label: Some("Instance Buffer"),
-        contents: bytemuck::cast_slice(&instance_data),
-        usage: wgpu::BufferUsages::VERTEX,
+        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+        size: 1024, // grown in `TexturePipeline::upload`
     });
 
     let texture_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
@@ -407,30 +958,130 @@ pub fn create_texture_pipeline(
         source: wgpu::ShaderSource::Wgsl(include_str!("shaders/texture_shader.wgsl").into()),
     });
 
-    let depth_texture = Texture::create_depth_texture(&device, &config, "depth_texture");
+    let [render_pipeline, unfilterable_pipeline] =
+        create_pipelines(device, config, sample_count, &texture_shader, &textures);
+
+    let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Vertex Buffer"),
+        contents: bytemuck::cast_slice(TEXTURE_VERTICES),
+        usage: wgpu::BufferUsages::VERTEX,
+    });
+    let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Index Buffer"),
+        contents: bytemuck::cast_slice(TEXTURE_INDICES),
+        usage: wgpu::BufferUsages::INDEX,
+    });
+    let num_indices = TEXTURE_INDICES.len() as u32;
+
+    TexturePipeline {
+        render_pipeline,
+        unfilterable_pipeline,
+        vertex_buffer,
+        index_buffer,
+        num_indices,
+        instance_buffer,
+        textures,
+        no_colormap,
+    }
+}
 
+/// A black texel, which needs no upload since textures start out zeroed.
+fn create_placeholder(device: &Device) -> Texture {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("placeholder"),
+        size: wgpu::Extent3d {
+            width: 1,
+            height: 1,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba8UnormSrgb,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    });
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let sampler = SamplerOptions::default().create_sampler(device);
+    Texture {
+        texture,
+        view,
+        sampler,
+    }
+}
+
+/// Creates the pipelines for filterable and unfilterable textures, which are all the
+/// pipelines using `shader`.
+fn create_pipelines(
+    device: &Device,
+    config: &SurfaceConfiguration,
+    sample_count: u32,
+    shader: &wgpu::ShaderModule,
+    textures: &TextureStore,
+) -> [wgpu::RenderPipeline; 2] {
+    let render_pipeline = create_render_pipeline(
+        device,
+        config,
+        sample_count,
+        shader,
+        &textures.bind_group_layout,
+        &textures.bind_group_layout,
+        "Render Pipeline",
+    );
+    let unfilterable_pipeline = create_render_pipeline(
+        device,
+        config,
+        sample_count,
+        shader,
+        &textures.unfilterable_bind_group_layout,
+        &textures.bind_group_layout,
+        "Unfilterable Render Pipeline",
+    );
+    [render_pipeline, unfilterable_pipeline]
+}
+
+/// Creates a pipeline drawing textures whose bind groups have `bind_group_layout`, looked
+/// up in colormaps bound with `colormap_layout`.
+fn create_render_pipeline(
+    device: &Device,
+    config: &SurfaceConfiguration,
+    sample_count: u32,
+    shader: &wgpu::ShaderModule,
+    bind_group_layout: &wgpu::BindGroupLayout,
+    colormap_layout: &wgpu::BindGroupLayout,
+    label: &str,
+) -> wgpu::RenderPipeline {
     let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-        label: Some("Render Pipeline Layout"),
-        bind_group_layouts: &[&texture_bind_group_layout],
+        label: Some(label),
+        bind_group_layouts: &[
+            bind_group_layout,
+            colormap_layout,
+            &view::create_bind_group_layout(device),
+        ],
         push_constant_ranges: &[],
     });
 
-    let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-        label: Some("Render Pipeline"),
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
         layout: Some(&render_pipeline_layout),
         vertex: wgpu::VertexState {
-            module: &texture_shader,
+            module: shader,
             entry_point: "vs_main",
             buffers: &[TextureVertex::desc(), TextureInstanceRaw::desc()],
         },
         fragment: Some(wgpu::FragmentState {
-            module: &texture_shader,
+            module: shader,
             entry_point: "fs_main",
             targets: &[Some(wgpu::ColorTargetState {
                 format: config.format,
+                // Blends like the tessellate pipeline, see `layer_shader.wgsl`.
                 blend: Some(wgpu::BlendState {
-                    color: wgpu::BlendComponent::REPLACE,
-                    alpha: wgpu::BlendComponent::REPLACE,
+                    color: wgpu::BlendComponent {
+                        src_factor: wgpu::BlendFactor::SrcAlpha,
+                        dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                        operation: wgpu::BlendOperation::Add,
+                    },
+                    alpha: wgpu::BlendComponent::OVER,
                 }),
                 write_mask: wgpu::ColorWrites::ALL,
             })],
@@ -439,7 +1090,8 @@ pub fn create_texture_pipeline(
             topology: wgpu::PrimitiveTopology::TriangleList,
             strip_index_format: None,
             front_face: wgpu::FrontFace::Ccw,
-            cull_mode: Some(wgpu::Face::Back),
+            // Transforms may mirror the quads.
+            cull_mode: None,
             // Setting this to anything other than Fill requires Features::POLYGON_MODE_LINE
             // or Features::POLYGON_MODE_POINT
             polygon_mode: wgpu::PolygonMode::Fill,
@@ -450,42 +1102,167 @@ pub fn create_texture_pipeline(
         },
         depth_stencil: Some(wgpu::DepthStencilState {
             format: Texture::DEPTH_FORMAT,
-            depth_write_enabled: true,
-            depth_compare: wgpu::CompareFunction::Less,
-            stencil: wgpu::StencilState::default(),
+            // Shapes are painted in order, later ones on top, see `Canvas::paint_order`.
+            depth_write_enabled: false,
+            depth_compare: wgpu::CompareFunction::Always,
+            // Clipped like the tessellated shapes, see `TessellatePipeline::draw`.
+            stencil: tessellate::clip_stencil_state(wgpu::StencilOperation::Keep, 0x00),
             bias: wgpu::DepthBiasState::default(),
         }),
         multisample: wgpu::MultisampleState {
-            count: 1,
+            count: sample_count,
             mask: !0,
             alpha_to_coverage_enabled: false,
         },
         // If the pipeline will be used with a multiview render pass, this
         // indicates how many array layers the attachments will have.
         multiview: None,
-    });
+    })
+}
 
-    let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some("Vertex Buffer"),
-        contents: bytemuck::cast_slice(TEXTURE_VERTICES),
-        usage: wgpu::BufferUsages::VERTEX,
-    });
-    let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some("Index Buffer"),
-        contents: bytemuck::cast_slice(TEXTURE_INDICES),
-        usage: wgpu::BufferUsages::INDEX,
-    });
-    let num_indices = TEXTURE_INDICES.len() as u32;
+impl TexturePipeline {
+    /// Recreates the pipelines with `shader`, a new build of `texture_shader.wgsl`. The
+    /// current pipelines are kept when that fails.
+    pub fn set_shader(
+        &mut self,
+        device: &Device,
+        config: &SurfaceConfiguration,
+        sample_count: u32,
+        shader: &wgpu::ShaderModule,
+    ) -> Result<()> {
+        let pipelines = reload::capture_errors(device, || {
+            create_pipelines(device, config, sample_count, shader, &self.textures)
+        })?;
+        [self.render_pipeline, self.unfilterable_pipeline] = pipelines;
+        Ok(())
+    }
 
-    TexturePipeline {
-        render_pipeline,
-        vertex_buffer,
-        index_buffer,
-        num_indices,
-        diffuse_texture,
-        diffuse_bind_group,
-        instances,
-        instance_buffer,
-        depth_texture,
+    /// Writes the instances of the `visible` shapes of `canvas` that are filled with a
+    /// texture and stores where they are in `ranges`, which come from
+    /// `TessellatePipeline::upload`.
+    pub fn upload(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        canvas: &Canvas,
+        visible: &[usize],
+        ranges: &mut [TessellateRange],
+    ) {
+        let mut instances: Vec<TextureInstanceRaw> = vec![];
+        for &shape in visible {
+            let tessellate = &canvas.tessellates[shape];
+            let Some(fill) = &tessellate.texture else {
+                continue;
+            };
+            let start = instances.len() as u32;
+            let nan_color = fill.colormap.as_ref().map(|_| fill.nan_color);
+            if fill.sprites.is_empty() {
+                let rect = fill.rect;
+                let instance = TextureInstance {
+                    transform: Transform::scale(rect.width(), rect.height())
+                        .then_translate(rect.min.to_vector())
+                        .then(&tessellate.transform),
+                    uv: fill.uv,
+                    color: tessellate.color,
+                    mapping: fill.mapping,
+                    nan_color,
+                };
+                instances.push(instance.to_raw());
+            }
+            instances.extend(fill.sprites.iter().map(|sprite| {
+                let instance = TextureInstance {
+                    transform: sprite.transform().then(&tessellate.transform),
+                    uv: sprite.uv,
+                    color: std::array::from_fn(|i| sprite.color[i] * tessellate.color[i]),
+                    mapping: fill.mapping,
+                    nan_color,
+                };
+                instance.to_raw()
+            }));
+            ranges[shape].instances = start..instances.len() as u32;
+        }
+
+        let data: &[u8] = bytemuck::cast_slice(&instances);
+        if data.len() as u64 > self.instance_buffer.size() {
+            self.instance_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Instance Buffer"),
+                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+                size: (data.len() as u64).next_power_of_two(),
+            });
+        }
+        if !data.is_empty() {
+            queue.write_buffer(&self.instance_buffer, 0, data);
+        }
+    }
+
+    /// Draws `instances` from the last `upload` with `texture` and `colormap`, inside
+    /// `clip_depth` clips.
+    pub fn draw_instances<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        texture: TextureId,
+        colormap: Option<TextureId>,
+        instances: Range<u32>,
+        clip_depth: usize,
+        view: &'a View,
+    ) {
+        let colormap = colormap.unwrap_or(self.no_colormap.id());
+        let (Some(bind_group), Some(colormap_bind_group)) = (
+            self.textures.bind_group(texture),
+            self.textures.bind_group(colormap),
+        ) else {
+            return;
+        };
+        if self.textures.is_filterable(texture) {
+            render_pass.set_pipeline(&self.render_pipeline);
+        } else {
+            render_pass.set_pipeline(&self.unfilterable_pipeline);
+        }
+        render_pass.set_bind_group(0, bind_group, &[]);
+        render_pass.set_bind_group(1, colormap_bind_group, &[]);
+        render_pass.set_bind_group(2, &view.bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+        render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
+        render_pass.set_stencil_reference(clip_depth as u32);
+        render_pass.draw_indexed(0..self.num_indices, 0, instances);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageBuffer, Luma, LumaA, Rgb};
+
+    fn floats(data: &[u8]) -> Vec<f32> {
+        data.chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn high_precision_images_keep_their_values() {
+        let luma = ImageBuffer::from_pixel(2, 1, Luma([0x1234u16]));
+        let (format, data) = data_texels(&DynamicImage::ImageLuma16(luma)).unwrap();
+        assert_eq!(format, DataFormat::R16);
+        assert_eq!(data, vec![0x34, 0x12, 0x34, 0x12]);
+
+        let luma_alpha = ImageBuffer::from_pixel(1, 1, LumaA([65535u16, 0]));
+        let (format, data) = data_texels(&DynamicImage::ImageLumaA16(luma_alpha)).unwrap();
+        assert_eq!(format, DataFormat::Rgba32F);
+        assert_eq!(floats(&data), vec![1.0, 1.0, 1.0, 0.0]);
+
+        let rgb = ImageBuffer::from_pixel(1, 1, Rgb([0.25f32, 1.5, -2.0]));
+        let (format, data) = data_texels(&DynamicImage::ImageRgb32F(rgb)).unwrap();
+        assert_eq!(format, DataFormat::Rgba32F);
+        assert_eq!(floats(&data), vec![0.25, 1.5, -2.0, 1.0]);
+    }
+
+    #[test]
+    fn eight_bit_images_are_not_data() {
+        let image = DynamicImage::new_rgba8(1, 1);
+        assert!(data_texels(&image).is_none());
+        assert!(data_texels(&DynamicImage::new_luma8(1, 1)).is_none());
     }
 }
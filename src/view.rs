@@ -0,0 +1,118 @@
+use wgpu::util::DeviceExt;
+use wgpu::{Device, Queue};
+
+use crate::canvas::ScissorRect;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct ViewUniform {
+    scale: [f32; 2],
+    offset: [f32; 2],
+}
+
+/// The part of the screen a render pass draws into. Layers are rendered into targets only
+/// as big as their bounds, so shapes are moved from canvas coordinates into the target by
+/// the vertex shaders, with the uniform of the view.
+pub struct View {
+    /// The screen position of the top left of the target, in pixels.
+    pub origin: [u32; 2],
+    /// The size of the part of the target that is drawn into, in pixels.
+    pub size: (u32, u32),
+    buffer: wgpu::Buffer,
+    pub bind_group: wgpu::BindGroup,
+}
+
+/// The layout of `View::bind_group`. Pipelines create their own, which is compatible since
+/// bind group layouts with the same entries are interchangeable.
+pub fn create_bind_group_layout(device: &Device) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::VERTEX,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        }],
+        label: Some("view_bind_group_layout"),
+    })
+}
+
+impl View {
+    /// A view of the whole screen, which draws canvas coordinates unchanged.
+    pub fn new(device: &Device, size: (u32, u32)) -> View {
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("View Uniform Buffer"),
+            contents: bytemuck::cast_slice(&[ViewUniform {
+                scale: [1.0; 2],
+                offset: [0.0; 2],
+            }]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &create_bind_group_layout(device),
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+            label: Some("view_bind_group"),
+        });
+        View {
+            origin: [0; 2],
+            size,
+            buffer,
+            bind_group,
+        }
+    }
+
+    /// Moves the view to `origin` on a `screen` of the given size, drawing into a target
+    /// of `texture_size`, of which the top left `size` pixels are used.
+    pub fn update(
+        &mut self,
+        queue: &Queue,
+        screen: (u32, u32),
+        origin: [u32; 2],
+        size: (u32, u32),
+        texture_size: (u32, u32),
+    ) {
+        self.origin = origin;
+        self.size = size;
+        // Canvas x from -1 to 1 covers the screen, which starts `origin` pixels before the
+        // target, whose own x runs from -1 to 1 across `texture_size`.
+        let (width, height) = (screen.0 as f32, screen.1 as f32);
+        let (texture_width, texture_height) = (texture_size.0 as f32, texture_size.1 as f32);
+        let uniform = ViewUniform {
+            scale: [width / texture_width, height / texture_height],
+            offset: [
+                (width - 2.0 * origin[0] as f32) / texture_width - 1.0,
+                1.0 - (height - 2.0 * origin[1] as f32) / texture_height,
+            ],
+        };
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    /// Moves `scissor`, given in screen pixels, into the target and clips it to the drawn
+    /// part of it.
+    pub fn scissor(&self, scissor: &ScissorRect) -> ScissorRect {
+        let [x, y] = self.origin;
+        let left = scissor.x.max(x) - x;
+        let top = scissor.y.max(y) - y;
+        let right = scissor.x.saturating_add(scissor.width).saturating_sub(x);
+        let bottom = scissor.y.saturating_add(scissor.height).saturating_sub(y);
+        let right = right.min(self.size.0);
+        let bottom = bottom.min(self.size.1);
+        ScissorRect::new(
+            left,
+            top,
+            right.saturating_sub(left),
+            bottom.saturating_sub(top),
+        )
+    }
+
+    /// The drawn part of the target.
+    pub fn full(&self) -> ScissorRect {
+        ScissorRect::new(0, 0, self.size.0, self.size.1)
+    }
+}
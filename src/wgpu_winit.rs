@@ -1,32 +1,84 @@
-use std::num::NonZeroU64;
-use std::time::SystemTime;
-use std::{iter, mem};
+use std::time::Instant;
 
-use bytemuck::cast_slice;
 use winit::event::{Event, WindowEvent};
-use winit::event_loop::{ControlFlow, EventLoop};
-use winit::window::{Window, WindowBuilder};
+use winit::event_loop::EventLoop;
+use winit::window::Window;
 
-use crate::canvas;
-use crate::tessellate::{self, TessellatePipeline, TessellateVertex};
-use crate::texture::{self, TexturePipeline};
+use crate::app::{App, Context};
+use crate::canvas::Canvas;
+use crate::input::Input;
+use crate::reload::{HotReload, ReloadEvent};
+use crate::renderer::{supported_sample_count, Renderer, RendererDescriptor};
 
-pub async fn run() {
-    env_logger::init();
+pub struct Config {
+    pub title: String,
+    /// The requested MSAA sample count (1, 2, 4 or 8). The highest count supported by the
+    /// adapter that does not exceed it is used.
+    pub sample_count: u32,
+    /// Keeps the canvas between frames instead of clearing it before every `App::draw`, so
+    /// the app only adds, changes and removes shapes and unchanged ones are not uploaded again.
+    pub retained: bool,
+    /// Watches the shaders of the source tree and the loaded image files, and reloads them
+    /// when they change, see `HotReload`. Meant for development.
+    pub hot_reload: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            title: "pinxerit".to_string(),
+            sample_count: 4,
+            retained: false,
+            hot_reload: false,
+        }
+    }
+}
+
+/// Opens a window and drives `app` from its event loop until the window is closed.
+pub async fn run<A: App>(mut app: A, config: Config) {
+    // The app may have installed a logger of its own already.
+    let _ = env_logger::try_init();
     let event_loop = EventLoop::new().unwrap();
-    let mut builder = winit::window::WindowBuilder::new();
+    let builder = winit::window::WindowBuilder::new().with_title(&config.title);
     let window = builder.build(&event_loop).unwrap();
 
-    let mut render_counter = 0;
     {
-        let mut state = new(&window).await;
+        let mut state = new(&window, &config).await;
+        let mut hot_reload = None;
+        if config.hot_reload {
+            match HotReload::new(HotReload::source_shader_dir()) {
+                Ok(reload) => hot_reload = Some(reload),
+                Err(error) => log::error!("hot reload is disabled: {error:#}"),
+            }
+        }
+        let (textures, materials) = state.renderer.resources_mut();
+        app.init(&mut Context {
+            device: &state.device,
+            queue: &state.queue,
+            window: &window,
+            textures,
+            materials,
+            hot_reload: hot_reload.as_mut(),
+        });
+        let mut last_frame = Instant::now();
         event_loop
             .run(|event, target| {
+                app.event(&event);
+                if let Event::AboutToWait = event {
+                    // Keep drawing frames for as long as the app runs.
+                    window.request_redraw();
+                }
                 if let Event::WindowEvent {
                     window_id: _,
                     event,
                 } = event
                 {
+                    for input_event in state
+                        .input
+                        .handle(&event, state.size, window.scale_factor())
+                    {
+                        app.input(&input_event);
+                    }
                     match event {
                         WindowEvent::Resized(mut new_size) => {
                             // Reconfigure the surface with the new size
@@ -40,12 +92,39 @@ pub async fn run() {
                             // On macos the window needs to be redrawn manually after resizing
                         }
                         WindowEvent::RedrawRequested => {
-                            // state.update();
+                            if let Some(reload) = &mut hot_reload {
+                                let events =
+                                    reload.poll(&state.device, &state.queue, &mut state.renderer);
+                                for event in events {
+                                    if let ReloadEvent::Changed(path) = event {
+                                        let (textures, materials) = state.renderer.resources_mut();
+                                        app.file_changed(
+                                            &mut Context {
+                                                device: &state.device,
+                                                queue: &state.queue,
+                                                window: &window,
+                                                textures,
+                                                materials,
+                                                hot_reload: Some(&mut *reload),
+                                            },
+                                            &path,
+                                        );
+                                    }
+                                }
+                            }
+                            let now = Instant::now();
+                            app.update(&state.input, now - last_frame);
+                            last_frame = now;
+
+                            if !config.retained {
+                                state.canvas.clear();
+                            }
+                            app.draw(&mut state.canvas);
                             match render(&mut state) {
                                 Ok(_) => {}
                                 // Reconfigure the surface if lost
                                 Err(wgpu::SurfaceError::Lost) => {
-                                    let size = state.size.clone();
+                                    let size = state.size;
                                     resize(&mut state, size)
                                 }
                                 // The system is out of memory, we should probably quit
@@ -53,6 +132,7 @@ pub async fn run() {
                                 // All other errors (Outdated, Timeout) should be resolved by the next frame
                                 Err(e) => eprintln!("{:?}", e),
                             }
+                            state.input.end_frame();
                         }
                         WindowEvent::CloseRequested => target.exit(),
                         _ => {}
@@ -73,11 +153,12 @@ struct State<'w> {
     queue: wgpu::Queue,
     config: wgpu::SurfaceConfiguration,
     size: winit::dpi::PhysicalSize<u32>,
-    texture_pipeline: TexturePipeline,
-    tessellate_pipeline: TessellatePipeline,
+    renderer: Renderer,
+    canvas: Canvas,
+    input: Input,
 }
 
-async fn new<'w>(window: &'w Window) -> State {
+async fn new<'w>(window: &'w Window, run_config: &Config) -> State<'w> {
     let size = window.inner_size();
 
     // The instance is a handle to our GPU
@@ -87,11 +168,9 @@ async fn new<'w>(window: &'w Window) -> State {
         ..Default::default()
     });
 
-    // # Safety
-    //
-    // The surface needs to live as long as the window that created it.
-    // State owns the window so this should be safe.
-    let surface = unsafe { instance.create_surface(window) }.unwrap();
+    // The surface needs to live as long as the window that created it,
+    // which the `'w` lifetime of State guarantees.
+    let surface = instance.create_surface(window).unwrap();
 
     let adapter = instance
         .request_adapter(&wgpu::RequestAdapterOptions {
@@ -105,7 +184,9 @@ async fn new<'w>(window: &'w Window) -> State {
         .request_device(
             &wgpu::DeviceDescriptor {
                 label: None,
-                required_features: wgpu::Features::empty(),
+                // Needed for sample counts other than 1 and 4.
+                required_features: adapter.features()
+                    & wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES,
                 required_limits: wgpu::Limits::default(),
             },
             None, // Trace path
@@ -135,8 +216,15 @@ async fn new<'w>(window: &'w Window) -> State {
     };
     surface.configure(&device, &config);
 
-    let texture_pipeline = texture::create_texture_pipeline(&device, &queue, &config);
-    let tessellate_pipeline = tessellate::create_tessellate_pipeline(&device, &queue, &config);
+    let renderer = Renderer::new(
+        &device,
+        &RendererDescriptor {
+            format: surface_format,
+            width: config.width,
+            height: config.height,
+            sample_count: supported_sample_count(&adapter, surface_format, run_config.sample_count),
+        },
+    );
 
     State {
         surface,
@@ -144,145 +232,31 @@ async fn new<'w>(window: &'w Window) -> State {
         queue,
         config,
         size,
-        texture_pipeline,
-        tessellate_pipeline,
+        renderer,
+        canvas: Canvas::new(),
+        input: Input::new(),
     }
 }
 
-fn update_vertices(state: &mut State) {
-    let mut encoder = state
-        .device
-        .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-            label: Some("Staging Belt Encoder"),
-        });
-}
-
 fn render(state: &mut State) -> Result<(), wgpu::SurfaceError> {
-    let time = SystemTime::now();
     let output = state.surface.get_current_texture()?;
     let view = output
         .texture
         .create_view(&wgpu::TextureViewDescriptor::default());
 
-    let mut encoder = state
-        .device
-        .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-            label: Some("Render Encoder"),
-        });
-
-    {
-        // let mut canvas = canvas::Canvas::new();
-        // let mut line = canvas::Line::start(-1., -1., [0.8, 0.2, 0.5, 1.0]);
-        // line.to(
-        //     (200. / state.size.width as f32) * 2. - 1.,
-        //     (0. / state.size.height as f32) * -2. + 1.,
-        // );
-        // line.to(
-        //     (200. / state.size.width as f32) * 2. - 1.,
-        //     (300. / state.size.height as f32) * -2. + 1.,
-        // );
-        // line.to(
-        //     (400. / state.size.width as f32) * 2. - 1.,
-        //     (300. / state.size.height as f32) * -2. + 1.,
-        // );
-        // line.to(
-        //     (400. / state.size.width as f32) * 2. - 1.,
-        //     (400. / state.size.height as f32) * -2. + 1.,
-        // );
-        // line.end(&mut canvas);
-
-        // {
-        //     let vertex_update_buffer = canvas.tessellates.first().unwrap().vertices.as_slice();
-        //     let mut vertex_target_buffer = state.tessellate_pipeline.staging_belt.write_buffer(
-        //         &mut encoder,
-        //         &state.tessellate_pipeline.vertex_buffer,
-        //         0,
-        //         NonZeroU64::new(
-        //             mem::size_of::<TessellateVertex>() as wgpu::BufferAddress
-        //                 * vertex_update_buffer.len() as u64,
-        //         )
-        //         .unwrap(),
-        //         &state.device,
-        //     );
-        //     vertex_target_buffer.copy_from_slice(cast_slice(&vertex_update_buffer));
-        // }
-        // {
-        //     let index_update_buffer = canvas.tessellates.first().unwrap().indices.as_slice();
-        //     let mut index_target_buffer = state.tessellate_pipeline.staging_belt.write_buffer(
-        //         &mut encoder,
-        //         &state.tessellate_pipeline.index_buffer,
-        //         0,
-        //         NonZeroU64::new(
-        //             mem::size_of::<u16>() as wgpu::BufferAddress * index_update_buffer.len() as u64,
-        //         )
-        //         .unwrap(),
-        //         &state.device,
-        //     );
-        //     index_target_buffer.copy_from_slice(cast_slice(&index_update_buffer));
-        // }
-        // state.tessellate_pipeline.staging_belt.finish();
-        // index_staging_belt.finish();
-        // --------------------------------------------------------
-
-        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-            label: Some("Render Pass"),
-            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: &view,
-                resolve_target: None,
-                ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(wgpu::Color {
-                        r: 0.1,
-                        g: 0.2,
-                        b: 0.3,
-                        a: 1.0,
-                    }),
-                    store: wgpu::StoreOp::Store,
-                },
-            })],
-            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                view: &state.texture_pipeline.depth_texture.view,
-                depth_ops: Some(wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(1.0),
-                    store: wgpu::StoreOp::Store,
-                }),
-                stencil_ops: None,
-            }),
-            occlusion_query_set: None,
-            timestamp_writes: None,
-        });
-
-        render_pass.set_pipeline(&state.texture_pipeline.render_pipeline);
-        render_pass.set_bind_group(0, &state.texture_pipeline.diffuse_bind_group, &[]);
-        render_pass.set_vertex_buffer(0, state.texture_pipeline.vertex_buffer.slice(..));
-        render_pass.set_vertex_buffer(1, state.texture_pipeline.instance_buffer.slice(..));
-        render_pass.set_index_buffer(
-            state.texture_pipeline.index_buffer.slice(..),
-            wgpu::IndexFormat::Uint16,
-        );
-        render_pass.draw_indexed(
-            0..state.texture_pipeline.num_indices,
-            0,
-            0..state.texture_pipeline.instances.len() as u32,
-        );
-
-        // TODO: remove -------------------------------------------------------
-        render_pass.set_pipeline(&state.tessellate_pipeline.render_pipeline);
-        render_pass.set_vertex_buffer(0, state.tessellate_pipeline.vertex_buffer.slice(..));
-        render_pass.set_index_buffer(
-            state.tessellate_pipeline.index_buffer.slice(..),
-            wgpu::IndexFormat::Uint16,
-        );
-        // render_pass.draw(0..6, 0..1);
-        render_pass.draw_indexed(0..12, 0, 0..1);
-        // --------------------------------------------------------------------
-    }
-
-    state.queue.submit(iter::once(encoder.finish()));
-    // index_staging_belt.recall();
-    state.tessellate_pipeline.staging_belt.recall();
+    state.renderer.render(
+        &state.device,
+        &state.queue,
+        &mut state.canvas,
+        &view,
+        wgpu::Color {
+            r: 0.1,
+            g: 0.2,
+            b: 0.3,
+            a: 1.0,
+        },
+    );
     output.present();
-
-    println!("{:?}", time.elapsed());
     Ok(())
 }
 
@@ -292,8 +266,8 @@ fn resize(state: &mut State, new_size: winit::dpi::PhysicalSize<u32>) {
         state.config.width = new_size.width;
         state.config.height = new_size.height;
         state.surface.configure(&state.device, &state.config);
-        // state.camera.aspect = state.config.width as f32 / state.config.height as f32;
-        state.texture_pipeline.depth_texture =
-            texture::Texture::create_depth_texture(&state.device, &state.config, "depth_texture");
+        state
+            .renderer
+            .resize(&state.device, new_size.width, new_size.height);
     }
 }